@@ -0,0 +1,78 @@
+use crate::codec::decode;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Cursor, Read, Seek};
+use std::path::Path;
+use zip::ZipArchive;
+
+/// Separates the path of an archive from the path of a file inside it in
+/// hit sources, as in `dump.zip!/inner/path.txt`.
+pub const MEMBER_SEPARATOR: &str = "!/";
+
+/// Enough of a tar stream to hold its first header's `ustar` magic.
+const TAR_HEADER_LEN: u64 = 512;
+
+/// Reads a data file as a virtual directory: each file inside a `.zip` or
+/// a (possibly compressed) tar is handed to `visit` with its path inside
+/// the archive, decompressed if it is itself compressed; any other file is
+/// handed over whole, with no member path. Members are streamed, so
+/// nothing is extracted to disk.
+pub(crate) fn for_each_member<R, F>(file: R, mut visit: F) -> io::Result<()>
+where
+    R: Read + Seek,
+    F: FnMut(Option<&str>, Box<dyn BufRead + '_>) -> io::Result<()>,
+{
+    let mut file = BufReader::new(file);
+    if file.fill_buf()?.starts_with(b"PK\x03\x04") {
+        let mut archive = ZipArchive::new(file).map_err(zip_error)?;
+        for i in 0..archive.len() {
+            let member = archive.by_index(i).map_err(zip_error)?;
+            if !member.is_file() {
+                continue;
+            }
+            let name = member.name().to_string();
+            visit(Some(&name), decode(BufReader::new(member))?)?;
+        }
+        return Ok(());
+    }
+
+    let mut decoded = decode(file)?;
+    let mut head = Vec::new();
+    decoded.by_ref().take(TAR_HEADER_LEN).read_to_end(&mut head)?;
+    let tar = is_tar(&head);
+    let stream = Cursor::new(head).chain(decoded);
+    if !tar {
+        return visit(None, Box::new(stream));
+    }
+    let mut archive = tar::Archive::new(stream);
+    for member in archive.entries()? {
+        let member = member?;
+        if !member.header().entry_type().is_file() {
+            continue;
+        }
+        let name = member.path()?.to_string_lossy().into_owned();
+        visit(Some(&name), decode(BufReader::new(member))?)?;
+    }
+    Ok(())
+}
+
+/// Whether the data file at `path` is a zip or tar archive.
+pub(crate) fn is_archive(path: &Path) -> io::Result<bool> {
+    let mut file = BufReader::new(File::open(path)?);
+    if file.fill_buf()?.starts_with(b"PK\x03\x04") {
+        return Ok(true);
+    }
+    let mut head = Vec::new();
+    decode(file)?.take(TAR_HEADER_LEN).read_to_end(&mut head)?;
+    Ok(is_tar(&head))
+}
+
+fn is_tar(head: &[u8]) -> bool {
+    head.get(257..262) == Some(b"ustar")
+}
+
+fn zip_error(err: zip::result::ZipError) -> io::Error {
+    match err {
+        zip::result::ZipError::Io(err) => err,
+        err => io::Error::new(io::ErrorKind::InvalidData, err),
+    }
+}
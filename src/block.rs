@@ -0,0 +1,167 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+/// A byte range of a compressed file that decodes on its own, such as a
+/// single zstd frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Block {
+    pub offset: u64,
+    pub len: u64,
+    /// Number of lines in the file before this block.
+    pub start_line: u64,
+    /// Number of decompressed bytes in the file before this block.
+    pub start_offset: u64,
+}
+
+impl Block {
+    /// Where the block starts within the decompressed file.
+    pub(crate) fn start(&self) -> Position {
+        Position {
+            lines: self.start_line,
+            bytes: self.start_offset,
+        }
+    }
+}
+
+/// A point in a decompressed file, as the lines and bytes before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) struct Position {
+    pub(crate) lines: u64,
+    pub(crate) bytes: u64,
+}
+
+/// Reads lines as raw bytes, without their line endings, each with the
+/// [`Position`] it starts at. Lines that are not valid UTF-8 are kept. A
+/// read error, such as a truncated compressed file, is returned once and
+/// ends the iteration.
+pub(crate) struct Lines<R> {
+    reader: R,
+    position: Position,
+    failed: bool,
+}
+
+impl<R: BufRead> Lines<R> {
+    /// Reads `reader`, whose first line is at `start` in its file.
+    pub(crate) fn new(reader: R, start: Position) -> Self {
+        Lines {
+            reader,
+            position: start,
+            failed: false,
+        }
+    }
+
+    /// The position after the last line read.
+    pub(crate) fn position(&self) -> Position {
+        self.position
+    }
+}
+
+impl<R: BufRead> Iterator for Lines<R> {
+    type Item = io::Result<(Position, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let mut line = Vec::new();
+        let read = match self.reader.read_until(b'\n', &mut line) {
+            Ok(0) => return None,
+            Ok(read) => read,
+            Err(err) => {
+                self.failed = true;
+                return Some(Err(err));
+            }
+        };
+        let start = self.position;
+        self.position.lines += 1;
+        self.position.bytes += read as u64;
+        if line.ends_with(b"\n") {
+            line.pop();
+            if line.ends_with(b"\r") {
+                line.pop();
+            }
+        }
+        Some(Ok((start, line)))
+    }
+}
+
+/// A piece of the dataset a scan has to read: a whole file, or just one
+/// block of it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanUnit {
+    pub path: PathBuf,
+    pub block: Option<Block>,
+}
+
+impl ScanUnit {
+    pub fn file(path: PathBuf) -> Self {
+        ScanUnit { path, block: None }
+    }
+}
+
+/// Size and modification time, used to detect changed shard files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Stamp {
+    pub(crate) size: u64,
+    mtime_secs: u64,
+    mtime_nanos: u32,
+}
+
+impl Stamp {
+    pub(crate) fn of(path: &Path) -> io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+        let mtime = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Ok(Stamp {
+            size: metadata.len(),
+            mtime_secs: mtime.as_secs(),
+            mtime_nanos: mtime.subsec_nanos(),
+        })
+    }
+}
+
+/// Splits a zstd file into its frames, decoding each in turn for `read`,
+/// so only a buffer of the file is held at a time. Start lines and offsets
+/// are left at zero for the caller to fill in from what it read.
+pub(crate) fn zstd_frames<T>(
+    file: File,
+    mut read: impl FnMut(&mut dyn BufRead) -> io::Result<T>,
+) -> io::Result<Vec<(Block, T)>> {
+    let size = file.metadata()?.len();
+    let mut file = BufReader::new(file);
+    let mut frames = Vec::new();
+    let mut offset = 0;
+    while offset < size {
+        let mut frame = BufReader::new(ZstdDecoder::with_buffer(&mut file)?.single_frame());
+        let value = read(&mut frame)?;
+        // The decoder only consumes the whole frame once it is read to the end.
+        io::copy(&mut frame, &mut io::sink())?;
+        drop(frame);
+        let end = file.stream_position()?;
+        if end == offset {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "zstd frame of no bytes"));
+        }
+        let block = Block {
+            offset,
+            len: end - offset,
+            start_line: 0,
+            start_offset: 0,
+        };
+        frames.push((block, value));
+        offset = end;
+    }
+    Ok(frames)
+}
+
+/// Opens a single zstd frame of `file`.
+pub(crate) fn open_zstd_block<'a>(mut file: impl Read + Seek + 'a, block: Block) -> io::Result<Box<dyn BufRead + 'a>> {
+    file.seek(SeekFrom::Start(block.offset))?;
+    let frame = BufReader::new(file.take(block.len));
+    Ok(Box::new(BufReader::new(ZstdDecoder::with_buffer(frame)?.single_frame())))
+}
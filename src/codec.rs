@@ -0,0 +1,97 @@
+use bzip2::read::MultiBzDecoder;
+use bzip2::write::BzEncoder;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use lz4_flex::frame::{FrameDecoder as Lz4Decoder, FrameEncoder as Lz4Encoder};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use xz2::read::XzDecoder;
+use xz2::write::XzEncoder;
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+/// zstd level of rewritten shards.
+const ZSTD_LEVEL: i32 = 9;
+
+/// How a data file is compressed, going by its first bytes rather than
+/// its name, so a mislabelled or extensionless file is still read right.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Codec {
+    Plain,
+    Gzip,
+    Zstd,
+    Xz,
+    Bzip2,
+    Lz4,
+}
+
+impl Codec {
+    /// Recognises the magic bytes at the start of `data`.
+    pub(crate) fn sniff(data: &[u8]) -> Codec {
+        match data {
+            [0x1f, 0x8b, ..] => Codec::Gzip,
+            [0x28, 0xb5, 0x2f, 0xfd, ..] => Codec::Zstd,
+            // A zstd skippable frame, which may precede the data frames.
+            [0x50..=0x5f, 0x2a, 0x4d, 0x18, ..] => Codec::Zstd,
+            [0xfd, b'7', b'z', b'X', b'Z', 0x00, ..] => Codec::Xz,
+            [b'B', b'Z', b'h', ..] => Codec::Bzip2,
+            [0x04, 0x22, 0x4d, 0x18, ..] => Codec::Lz4,
+            _ => Codec::Plain,
+        }
+    }
+
+    /// The codec of the file at `path`.
+    pub(crate) fn of(path: &Path) -> io::Result<Codec> {
+        let mut reader = BufReader::with_capacity(8, File::open(path)?);
+        Ok(Codec::sniff(reader.fill_buf()?))
+    }
+
+    /// Compresses `data` as one self-contained frame, member or stream.
+    /// Concatenated, these decode as one file with [`decode`].
+    pub(crate) fn encode(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Codec::Plain => Ok(data.to_vec()),
+            Codec::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Codec::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL),
+            Codec::Xz => {
+                let mut encoder = XzEncoder::new(Vec::new(), 6);
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Codec::Bzip2 => {
+                let mut encoder = BzEncoder::new(Vec::new(), bzip2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Codec::Lz4 => {
+                let mut encoder = Lz4Encoder::new(Vec::new());
+                encoder.write_all(data)?;
+                encoder.finish().map_err(io::Error::from)
+            }
+        }
+    }
+}
+
+/// Opens a data file for reading its decompressed lines, whichever way it
+/// is compressed. Streams made of several members or frames, as written
+/// by parallel compressors, are read to the end.
+pub(crate) fn open_decoded(path: &Path) -> io::Result<Box<dyn BufRead>> {
+    decode(BufReader::new(File::open(path)?))
+}
+
+/// Decompresses `reader` with the codec its first bytes identify.
+pub(crate) fn decode<'a>(mut reader: impl BufRead + 'a) -> io::Result<Box<dyn BufRead + 'a>> {
+    let codec = Codec::sniff(reader.fill_buf()?);
+    Ok(match codec {
+        Codec::Plain => Box::new(reader),
+        Codec::Gzip => Box::new(BufReader::new(MultiGzDecoder::new(reader))),
+        Codec::Zstd => Box::new(BufReader::new(ZstdDecoder::with_buffer(reader)?)),
+        Codec::Xz => Box::new(BufReader::new(XzDecoder::new_multi_decoder(reader))),
+        Codec::Bzip2 => Box::new(BufReader::new(MultiBzDecoder::new(reader))),
+        Codec::Lz4 => Box::new(BufReader::new(Lz4Decoder::new(reader))),
+    })
+}
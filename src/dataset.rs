@@ -0,0 +1,159 @@
+use crate::error::FileError;
+use crate::index::INDEX_FILE;
+use crate::manifest::{Manifest, MANIFEST_FILE};
+use crate::shard::{FRAMES_SUFFIX, TEMP_SUFFIX};
+use std::io;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// A directory of breach data sharded by the start of each address, as
+/// described by its [`Manifest`].
+#[derive(Debug, Clone)]
+pub struct Dataset {
+    root: PathBuf,
+    manifest: Manifest,
+    name: String,
+}
+
+impl Dataset {
+    /// Opens the dataset rooted at `root`, failing if it is not a directory
+    /// or its `dataset.toml` is invalid.
+    pub fn open(root: impl Into<PathBuf>) -> io::Result<Self> {
+        let root = root.into();
+        if !root.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Could not find a directory at {}", root.display()),
+            ));
+        }
+        let manifest = Manifest::load(&root)?;
+        let name = match &manifest.name {
+            Some(name) => name.clone(),
+            None => root
+                .canonicalize()
+                .unwrap_or_else(|_| root.clone())
+                .file_name()
+                .map_or_else(|| root.to_string_lossy(), |name| name.to_string_lossy())
+                .into_owned(),
+        };
+        Ok(Dataset { root, manifest, name })
+    }
+
+    /// Names the dataset `name` instead of what its manifest says.
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    /// The name hits from the dataset are tagged with: the manifest's
+    /// name for it, or else the name of its root directory.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Every data file below the dataset root, skipping the tool's own
+    /// index files and shards still being written, along with the
+    /// directories that could not be read.
+    pub fn walk(&self) -> (Vec<PathBuf>, Vec<FileError>) {
+        let mut files = Vec::new();
+        let mut errors = Vec::new();
+        for entry in WalkDir::new(&self.root) {
+            match entry {
+                Ok(entry) if entry.path().is_file() && !is_metadata(entry.path()) => files.push(entry.into_path()),
+                Ok(_) => {}
+                Err(err) => {
+                    let path = err.path().unwrap_or(&self.root).to_path_buf();
+                    let message = err.to_string();
+                    let error = err.into_io_error().unwrap_or_else(|| io::Error::other(message));
+                    errors.push(FileError { path, error });
+                }
+            }
+        }
+        (files, errors)
+    }
+
+    /// Every data file below the dataset root, as [`Dataset::walk`] lists
+    /// them, failing at the first directory that could not be read.
+    pub fn files(&self) -> Result<Vec<PathBuf>, FileError> {
+        let (files, errors) = self.walk();
+        match errors.into_iter().next() {
+            Some(err) => Err(err),
+            None => Ok(files),
+        }
+    }
+
+    /// `path` relative to the dataset root, `/`-separated.
+    pub fn relative_path(&self, path: &Path) -> String {
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// Resolves the shard file that holds entries starting with `email`.
+    ///
+    /// Shards are nested one directory per character of the address's key
+    /// (see [`Manifest::depth`]), with the last character naming the file;
+    /// a shorter prefix wins when a shard file exists at that level. The
+    /// error names the file that was expected when there is none.
+    pub fn shard_path(&self, email: &str) -> Result<PathBuf, FileError> {
+        let key = self.manifest.key(email);
+        let extensions = self.manifest.codec.extensions();
+        let mut path = self.root.clone();
+        for part in &key {
+            path.push(part);
+            for extension in extensions {
+                let mut file = path.clone().into_os_string();
+                file.push(extension);
+                let file = PathBuf::from(file);
+                if file.is_file() {
+                    return Ok(file);
+                }
+            }
+        }
+
+        let mut expected = path.into_os_string();
+        if let [extension] = extensions {
+            expected.push(extension);
+        }
+        Err(FileError {
+            path: expected.into(),
+            error: io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no shard for addresses starting with '{}'", key.concat()),
+            ),
+        })
+    }
+
+    /// Where a new shard for `email` goes when [`Dataset::shard_path`]
+    /// finds none: at the full depth of its key, with the extension of the
+    /// codec new shards are written in.
+    pub(crate) fn new_shard_path(&self, email: &str) -> PathBuf {
+        let mut path = self.root.clone();
+        path.extend(self.manifest.key(email));
+        let mut path = path.into_os_string();
+        path.push(self.manifest.codec.for_new_shards().1);
+        path.into()
+    }
+}
+
+fn is_metadata(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| {
+            name == INDEX_FILE
+                || name.ends_with(FRAMES_SUFFIX)
+                || name.ends_with(TEMP_SUFFIX)
+                || name == MANIFEST_FILE
+        })
+}
@@ -0,0 +1,436 @@
+use crate::domain::Account;
+use crate::import::normalize_email;
+use crate::record::{Record, RecordParser};
+use crate::redact::Redaction;
+use crate::search::{Hit, Searcher};
+use crate::sort::{ExternalSorter, Sorted};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Separates the fields of a spooled hit.
+const SEPARATOR: u8 = 0;
+
+/// Escapes [`SEPARATOR`] in the key fields of a spooled hit.
+const ESCAPE: u8 = 1;
+
+/// Fields of a spooled hit after its key: dataset, source, line number,
+/// offset, patterns and the line itself.
+const HIT_FIELDS: usize = 6;
+
+/// Which hits [`HitSpool`] treats as copies of one another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Dedupe {
+    /// Hits with byte-for-byte equal lines.
+    #[default]
+    Exact,
+    /// Hits for the same account and password, comparing email addresses
+    /// once trimmed and lowercased, however the rest of the line differs.
+    Email,
+}
+
+impl FromStr for Dedupe {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.trim() {
+            "exact" => Ok(Dedupe::Exact),
+            "email" => Ok(Dedupe::Email),
+            other => Err(format!("unknown dedupe mode '{}'", other)),
+        }
+    }
+}
+
+/// Every distinct password found for one account, as collected by
+/// [`HitSpool::group`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupedAccount {
+    /// The normalised email address, or the username of records without
+    /// one.
+    pub email: String,
+    pub passwords: Vec<PasswordHits>,
+}
+
+/// One password of a [`GroupedAccount`] and every hit it was seen in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PasswordHits {
+    /// Empty for records without a password.
+    pub password: String,
+    pub hits: Vec<Hit>,
+}
+
+/// Collects hits on disk so that copies can be dropped, or hits grouped by
+/// account, however many there are: hits are buffered up to a memory limit
+/// and spilled to sorted runs by an [`ExternalSorter`], and come back out
+/// ordered by their line, or by account and password.
+///
+/// Of several copies of a hit, the one from the first dataset and source,
+/// by name, is kept.
+pub struct HitSpool {
+    dedupe: Dedupe,
+    redaction: Redaction,
+    memory_limit: usize,
+    temp_dir: PathBuf,
+    sorter: Option<ExternalSorter>,
+}
+
+impl HitSpool {
+    /// A spool ordering hits so that the copies `dedupe` describes are
+    /// adjacent. Grouping needs [`Dedupe::Email`].
+    pub fn new(dedupe: Dedupe) -> Self {
+        HitSpool {
+            dedupe,
+            redaction: Redaction::None,
+            memory_limit: 512 << 20,
+            temp_dir: std::env::temp_dir(),
+            sorter: None,
+        }
+    }
+
+    /// Holds up to `bytes` of hits in memory (512 MiB by default) before
+    /// spilling sorted runs to disk.
+    pub fn with_memory_limit(mut self, bytes: usize) -> Self {
+        self.memory_limit = bytes;
+        self
+    }
+
+    /// Spills hits under `temp_dir` rather than the system's temporary
+    /// directory.
+    pub fn with_temp_dir(mut self, temp_dir: impl Into<PathBuf>) -> Self {
+        self.temp_dir = temp_dir.into();
+        self
+    }
+
+    /// Spools hits with their passwords redacted, so they only reach the
+    /// disk as the output shows them, and hands them back redacted. Copies
+    /// are then told apart by SHA-256 digests of their raw lines or
+    /// passwords.
+    pub fn with_redaction(mut self, redaction: Redaction) -> Self {
+        self.redaction = redaction;
+        self
+    }
+
+    pub fn push(&mut self, hit: &Hit) -> io::Result<()> {
+        let record = &hit.record;
+        let line = self.redaction.raw_line(record);
+        let mut entry = Vec::with_capacity(2 * line.len() + hit.source.len() + 64);
+        match self.dedupe {
+            Dedupe::Exact => escape(&mut entry, &self.key(record.bytes())),
+            Dedupe::Email => {
+                escape(&mut entry, account(record).as_bytes());
+                entry.push(SEPARATOR);
+                escape(&mut entry, &self.key(password(record)));
+            }
+        }
+        let patterns = serde_json::to_string(&hit.patterns)?;
+        let line_number = format!("{:020}", hit.line_number);
+        let offset = format!("{:020}", hit.offset);
+        let fields: [&[u8]; HIT_FIELDS] = [
+            hit.dataset.as_bytes(),
+            hit.source.as_bytes(),
+            line_number.as_bytes(),
+            offset.as_bytes(),
+            patterns.as_bytes(),
+            &line,
+        ];
+        for field in fields {
+            entry.push(SEPARATOR);
+            entry.extend_from_slice(field);
+        }
+        let sorter = match &mut self.sorter {
+            Some(sorter) => sorter,
+            None => self.sorter.insert(ExternalSorter::new(&self.temp_dir, self.memory_limit)?),
+        };
+        sorter.push(entry)
+    }
+
+    /// What tells copies apart by `raw`, a line or password: itself, or its
+    /// digest if passwords are redacted, so that they stay off the disk.
+    fn key<'a>(&self, raw: &'a [u8]) -> Cow<'a, [u8]> {
+        match self.redaction {
+            Redaction::None => raw.into(),
+            _ => Sha256::digest(raw).to_vec().into(),
+        }
+    }
+
+    /// The hits pushed, without copies, ordered by their line (or its
+    /// digest, if passwords are redacted) or by account. Each is split into a record again with the parser
+    /// `searcher` uses for its dataset.
+    pub fn dedupe(self, searcher: &Searcher) -> io::Result<DedupedHits> {
+        Ok(DedupedHits {
+            spooled: self.finish(searcher)?,
+            last: None,
+        })
+    }
+
+    /// The hits pushed, grouped by account and then by password, in order
+    /// of email address.
+    pub fn group(self, searcher: &Searcher) -> io::Result<GroupedAccounts> {
+        Ok(GroupedAccounts {
+            spooled: self.finish(searcher)?,
+            pending: None,
+        })
+    }
+
+    /// Every hit pushed, gathered by account in order of email address, for
+    /// a per-account report. Needs [`Dedupe::Email`].
+    pub fn accounts(self, searcher: &Searcher) -> io::Result<SpooledAccounts> {
+        Ok(SpooledAccounts {
+            grouped: self.group(searcher)?,
+        })
+    }
+
+    fn finish(self, searcher: &Searcher) -> io::Result<Spooled> {
+        Ok(Spooled {
+            sorted: self.sorter.map(ExternalSorter::finish).transpose()?,
+            key_fields: match self.dedupe {
+                Dedupe::Exact => 1,
+                Dedupe::Email => 2,
+            },
+            parsers: searcher
+                .datasets()
+                .iter()
+                .zip(searcher.parsers())
+                .map(|(dataset, parser)| (dataset.name().to_string(), parser.clone()))
+                .collect(),
+        })
+    }
+}
+
+/// The sorted entries of a [`HitSpool`], turned back into hits.
+struct Spooled {
+    sorted: Option<Sorted>,
+    key_fields: usize,
+    parsers: HashMap<String, RecordParser>,
+}
+
+impl Spooled {
+    /// The next hit, with its key fields.
+    fn next(&mut self) -> Option<io::Result<(Vec<Vec<u8>>, Hit)>> {
+        let entry = match self.sorted.as_mut()?.next()? {
+            Ok(entry) => entry,
+            Err(err) => return Some(Err(err)),
+        };
+        let mut fields = entry.splitn(self.key_fields + HIT_FIELDS, |&byte| byte == SEPARATOR);
+        let key = fields.by_ref().take(self.key_fields).map(<[u8]>::to_vec).collect();
+        let [dataset, source, line_number, offset, patterns, line] = [(); HIT_FIELDS].map(|()| {
+            fields.next().unwrap_or_default()
+        });
+        let text = |field: &[u8]| String::from_utf8_lossy(field).into_owned();
+        let dataset = text(dataset);
+        let record = match self.parsers.get(&dataset) {
+            Some(parser) => parser.parse_bytes(line.to_vec()),
+            None => RecordParser::default().parse_bytes(line.to_vec()),
+        };
+        let hit = Hit {
+            record,
+            dataset,
+            source: text(source),
+            line_number: text(line_number).parse().unwrap_or_default(),
+            offset: text(offset).parse().unwrap_or_default(),
+            patterns: serde_json::from_slice(patterns).unwrap_or_default(),
+        };
+        Some(Ok((key, hit)))
+    }
+}
+
+/// The hits of a [`HitSpool`] without copies, from [`HitSpool::dedupe`].
+pub struct DedupedHits {
+    spooled: Spooled,
+    last: Option<Vec<Vec<u8>>>,
+}
+
+impl Iterator for DedupedHits {
+    type Item = io::Result<Hit>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (key, hit) = match self.spooled.next()? {
+                Ok(next) => next,
+                Err(err) => return Some(Err(err)),
+            };
+            if self.last.as_ref() != Some(&key) {
+                self.last = Some(key);
+                return Some(Ok(hit));
+            }
+        }
+    }
+}
+
+/// The accounts of a [`HitSpool`], from [`HitSpool::group`].
+pub struct GroupedAccounts {
+    spooled: Spooled,
+    /// The first hit of the next account, read while ending the last one.
+    pending: Option<(Vec<Vec<u8>>, Hit)>,
+}
+
+impl Iterator for GroupedAccounts {
+    type Item = io::Result<GroupedAccount>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (first_key, first) = match self.pending.take() {
+            Some(pending) => pending,
+            None => match self.spooled.next()? {
+                Ok(next) => next,
+                Err(err) => return Some(Err(err)),
+            },
+        };
+        let mut group = GroupedAccount {
+            email: account(&first.record),
+            passwords: Vec::new(),
+        };
+        let mut last_key = first_key;
+        group.passwords.push(PasswordHits {
+            password: first.record.password().unwrap_or_default().to_string(),
+            hits: vec![first],
+        });
+        loop {
+            let (key, hit) = match self.spooled.next() {
+                Some(Ok(next)) => next,
+                Some(Err(err)) => return Some(Err(err)),
+                None => break,
+            };
+            if key.first() != last_key.first() {
+                self.pending = Some((key, hit));
+                break;
+            }
+            if key == last_key {
+                group.passwords.last_mut().unwrap().hits.push(hit);
+            } else {
+                group.passwords.push(PasswordHits {
+                    password: hit.record.password().unwrap_or_default().to_string(),
+                    hits: vec![hit],
+                });
+            }
+            last_key = key;
+        }
+        Some(Ok(group))
+    }
+}
+
+/// The accounts of a [`HitSpool`] with all of their hits, from
+/// [`HitSpool::accounts`].
+pub struct SpooledAccounts {
+    grouped: GroupedAccounts,
+}
+
+impl Iterator for SpooledAccounts {
+    type Item = io::Result<Account>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.grouped.next()?.map(|group| Account {
+            email: group.email,
+            hits: group.passwords.into_iter().flat_map(|password| password.hits).collect(),
+        }))
+    }
+}
+
+/// The account a record belongs to: its normalised email address, or else
+/// its username, or else its whole line.
+fn account(record: &Record) -> String {
+    match (record.email(), record.username()) {
+        (Some(email), _) => normalize_email(email),
+        (None, Some(username)) => username.to_string(),
+        (None, None) => record.line().to_string(),
+    }
+}
+
+/// The password of a record as the bytes read from the dump.
+fn password(record: &Record) -> &[u8] {
+    match record.password_range() {
+        Some(range) => &record.bytes()[record.raw_range(range)],
+        None => &[],
+    }
+}
+
+/// Appends `field` to `entry` with [`SEPARATOR`] and [`ESCAPE`] escaped,
+/// so that it can be told from the fields after it.
+fn escape(entry: &mut Vec<u8>, field: &[u8]) {
+    for &byte in field {
+        match byte {
+            SEPARATOR | ESCAPE => entry.extend_from_slice(&[ESCAPE, byte + 1]),
+            byte => entry.push(byte),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dataset::Dataset;
+    use crate::record::RecordParser;
+    use crate::sort::Scratch;
+    use std::fs;
+    use std::io::Read;
+    use std::path::Path;
+
+    fn hit(line: &str) -> Hit {
+        Hit {
+            record: RecordParser::new().parse(line),
+            dataset: "leak".to_string(),
+            source: "one.txt".to_string(),
+            line_number: 1,
+            offset: 0,
+            patterns: Vec::new(),
+        }
+    }
+
+    /// Everything spilled under `dir`, decompressed.
+    fn spilled(dir: &Path) -> Vec<u8> {
+        let mut spilled = Vec::new();
+        for entry in walkdir::WalkDir::new(dir) {
+            let entry = entry.unwrap();
+            if entry.file_type().is_file() {
+                zstd::stream::read::Decoder::new(fs::File::open(entry.path()).unwrap())
+                    .unwrap()
+                    .read_to_end(&mut spilled)
+                    .unwrap();
+            }
+        }
+        spilled
+    }
+
+    #[test]
+    fn masked_passwords_stay_off_the_disk_and_apart() {
+        let scratch = Scratch::new();
+        let searcher = Searcher::new(Dataset::open(scratch.path()).unwrap());
+        for dedupe in [Dedupe::Exact, Dedupe::Email] {
+            let mut spool = HitSpool::new(dedupe)
+                .with_redaction(Redaction::Mask)
+                .with_memory_limit(0)
+                .with_temp_dir(scratch.path());
+            for line in ["bob@x.com:secret1", "bob@x.com:sEcret1", "bob@x.com:secret1"] {
+                spool.push(&hit(line)).unwrap();
+            }
+            let spilled = spilled(scratch.path());
+            assert!(!spilled.is_empty());
+            assert!(!spilled.windows(6).any(|window| window.eq_ignore_ascii_case(b"secret")));
+
+            let lines: Vec<String> = spool
+                .dedupe(&searcher)
+                .unwrap()
+                .map(|hit| hit.unwrap().record.line().to_string())
+                .collect();
+            assert_eq!(lines, ["bob@x.com:s*****1", "bob@x.com:s*****1"]);
+        }
+    }
+
+    #[test]
+    fn accounts_keep_every_hit_in_email_order() {
+        let scratch = Scratch::new();
+        let searcher = Searcher::new(Dataset::open(scratch.path()).unwrap());
+        let mut spool = HitSpool::new(Dedupe::Email).with_temp_dir(scratch.path());
+        for (i, line) in ["bob@x.com:pw", "Alice@x.com:pw", "bob@x.com:pw", "alice@x.com:other"].iter().enumerate() {
+            spool.push(&Hit { line_number: i as u64 + 1, ..hit(line) }).unwrap();
+        }
+        let accounts: Vec<(String, usize)> = spool
+            .accounts(&searcher)
+            .unwrap()
+            .map(|account| account.map(|account| (account.email, account.hits.len())).unwrap())
+            .collect();
+        assert_eq!(accounts, [("alice@x.com".to_string(), 2), ("bob@x.com".to_string(), 2)]);
+    }
+}
@@ -0,0 +1,75 @@
+use crate::record::Record;
+use crate::search::Hit;
+use aho_corasick::{AhoCorasick, AhoCorasickBuilder};
+
+/// Finds the accounts registered at a domain.
+///
+/// Unlike a keyword scan for `example.com`, only the domain part of each
+/// line's email field is compared, so the domain appearing inside a
+/// password or URL does not count.
+#[derive(Debug)]
+pub struct DomainQuery {
+    domain: String,
+    include_subdomains: bool,
+    prefilter: AhoCorasick,
+}
+
+impl DomainQuery {
+    pub fn new(domain: &str) -> Self {
+        let domain = domain.trim().trim_start_matches('@').to_lowercase();
+        let prefilter = AhoCorasickBuilder::new()
+            .ascii_case_insensitive(true)
+            .build([&domain]);
+        DomainQuery {
+            domain,
+            include_subdomains: false,
+            prefilter,
+        }
+    }
+
+    /// Also match addresses at any subdomain, e.g. `mail.example.com`.
+    pub fn include_subdomains(mut self, include_subdomains: bool) -> Self {
+        self.include_subdomains = include_subdomains;
+        self
+    }
+
+    pub fn domain(&self) -> &str {
+        &self.domain
+    }
+
+    pub fn includes_subdomains(&self) -> bool {
+        self.include_subdomains
+    }
+
+    pub fn matches_email(&self, email: &str) -> bool {
+        email
+            .rsplit_once('@')
+            .is_some_and(|(_, domain)| self.matches_domain(domain))
+    }
+
+    pub fn matches_domain(&self, domain: &str) -> bool {
+        let domain = domain.to_lowercase();
+        domain == self.domain
+            || (self.include_subdomains
+                && domain
+                    .strip_suffix(self.domain.as_str())
+                    .is_some_and(|subdomain| subdomain.ends_with('.')))
+    }
+
+    /// A cheap check that rules out lines that cannot mention the domain.
+    pub fn could_match(&self, line: impl AsRef<[u8]>) -> bool {
+        self.prefilter.is_match(line.as_ref())
+    }
+
+    pub fn matches(&self, record: &Record) -> bool {
+        record.email().is_some_and(|email| self.matches_email(email))
+    }
+}
+
+/// Every hit for one account, as reported by [`crate::Searcher::search_domain`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Account {
+    /// The lowercased email address.
+    pub email: String,
+    pub hits: Vec<Hit>,
+}
@@ -0,0 +1,44 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A file of the dataset, or of its index, that could not be read or
+/// written, such as a truncated `.gz` or an unreadable shard.
+#[derive(Debug)]
+pub struct FileError {
+    pub path: PathBuf,
+    pub error: io::Error,
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.error)
+    }
+}
+
+impl Error for FileError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl From<FileError> for io::Error {
+    fn from(err: FileError) -> Self {
+        io::Error::new(err.error.kind(), err.to_string())
+    }
+}
+
+/// Tags the error of an I/O result with the file it concerns.
+pub(crate) trait AtPath<T> {
+    fn at(self, path: &Path) -> Result<T, FileError>;
+}
+
+impl<T> AtPath<T> for io::Result<T> {
+    fn at(self, path: &Path) -> Result<T, FileError> {
+        self.map_err(|error| FileError {
+            path: path.to_path_buf(),
+            error,
+        })
+    }
+}
@@ -0,0 +1,277 @@
+use crate::archive;
+use crate::block::{Lines, Position};
+use crate::codec::Codec;
+use crate::dataset::Dataset;
+use crate::error::{AtPath, FileError};
+use crate::manifest::{Manifest, ShardCodec, MANIFEST_FILE};
+use crate::record::RecordParser;
+use crate::shard::ShardWriter;
+use crate::sort::ExternalSorter;
+use crate::stats::{LineCounter, Meter, Metered};
+use indicatif::ProgressBar;
+use rayon::prelude::*;
+use serde::Serialize;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use walkdir::WalkDir;
+
+/// Entries each reader collects before handing them to the shared sorter.
+const SORT_BATCH: usize = 1 << 14;
+
+/// Separates the shard of an entry from its line while sorting.
+const SHARD_SEPARATOR: u8 = 0;
+
+/// What an import read and wrote.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ImportStats {
+    /// Raw files read.
+    pub files: u64,
+    pub lines: u64,
+    /// Distinct records written to shards.
+    pub records: u64,
+    /// Records dropped as copies of another.
+    pub duplicates: u64,
+    /// Lines dropped for having no email address.
+    pub rejected: u64,
+    /// Shards written.
+    pub shards: u64,
+}
+
+/// Turns raw dumps into a sharded dataset: every line is split with the
+/// [`RecordParser`], its email normalised, and the `email:password` record
+/// routed to the shard its [`Manifest`] names. Each shard is sorted and
+/// deduplicated and written as multi-frame `.zst` with a frame sidecar.
+///
+/// Records pass through an [`ExternalSorter`], so memory use stays within
+/// the configured limit however large the dumps are.
+pub struct Importer {
+    target: PathBuf,
+    manifest: Manifest,
+    parser: RecordParser,
+    memory_limit: usize,
+    temp_dir: PathBuf,
+    meter: Meter,
+}
+
+impl Importer {
+    /// An importer writing to the dataset directory `target`, which must
+    /// not hold any shards yet. Shards are laid out as the manifest there
+    /// says, or as the default one, which is then written along with them.
+    pub fn new(target: impl Into<PathBuf>) -> io::Result<Self> {
+        let target = target.into();
+        let manifest = Manifest::load(&target)?;
+        if !matches!(manifest.codec, ShardCodec::Auto | ShardCodec::Zstd) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{}: imports write zstd shards, not {:?}", target.join(MANIFEST_FILE).display(), manifest.codec),
+            ));
+        }
+        if target.is_dir() && !Dataset::open(&target)?.files()?.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already holds shards; import into a new directory and merge it in", target.display()),
+            ));
+        }
+        Ok(Importer {
+            target,
+            manifest,
+            parser: RecordParser::default(),
+            memory_limit: 512 << 20,
+            temp_dir: std::env::temp_dir(),
+            meter: Meter::new(),
+        })
+    }
+
+    /// Splits raw lines with `parser` instead of the default auto-detecting
+    /// one.
+    pub fn with_parser(mut self, parser: RecordParser) -> Self {
+        self.parser = parser;
+        self
+    }
+
+    /// Sorts in up to `bytes` of memory (512 MiB by default) before
+    /// spilling sorted runs to disk.
+    pub fn with_memory_limit(mut self, bytes: usize) -> Self {
+        self.memory_limit = bytes;
+        self
+    }
+
+    /// Spills sorted runs under `temp_dir` instead of the system's
+    /// temporary directory.
+    pub fn with_temp_dir(mut self, temp_dir: impl Into<PathBuf>) -> Self {
+        self.temp_dir = temp_dir.into();
+        self
+    }
+
+    /// Reports reading progress on `progress`, in compressed bytes.
+    pub fn with_progress(mut self, progress: ProgressBar) -> Self {
+        self.meter.progress = Some(progress);
+        self
+    }
+
+    /// Imports the raw dumps at `sources`, which may be files, archives or
+    /// directories of them. The first unreadable file ends the import.
+    pub fn import(&self, sources: &[PathBuf]) -> Result<ImportStats, FileError> {
+        let mut files = Vec::new();
+        for source in sources {
+            for entry in WalkDir::new(source) {
+                let entry = entry.map_err(io::Error::from).at(source)?;
+                if entry.file_type().is_file() {
+                    files.push(entry.into_path());
+                }
+            }
+        }
+        let sorter = ExternalSorter::new(&self.temp_dir, self.memory_limit).at(&self.temp_dir)?.dedup();
+        let temp = sorter.path().to_path_buf();
+        let (pushed, rejected) = sort_lines(&files, &self.meter, &sorter, |line| self.entry(line))?;
+
+        let sorted = sorter.finish().at(&temp)?;
+        let mut stats = ImportStats {
+            files: files.len() as u64,
+            lines: self.meter.stats().lines,
+            rejected,
+            ..ImportStats::default()
+        };
+        let mut current: Option<(Vec<u8>, ShardWriter, PathBuf)> = None;
+        for entry in sorted {
+            let entry = entry.at(&temp)?;
+            let at = entry.iter().position(|&byte| byte == SHARD_SEPARATOR).unwrap_or_default();
+            let (shard, line) = (&entry[..at], &entry[at + 1..]);
+            if current.as_ref().is_none_or(|(current, _, _)| current != shard) {
+                if let Some((_, writer, path)) = current.take() {
+                    writer.finish().at(&path)?;
+                    stats.shards += 1;
+                }
+                let path = self.shard_path(shard);
+                let writer = ShardWriter::create(&path, Codec::Zstd).at(&path)?;
+                current = Some((shard.to_vec(), writer, path));
+            }
+            let (_, writer, path) = current.as_mut().unwrap();
+            writer.write_line(line).at(path)?;
+            stats.records += 1;
+        }
+        if let Some((_, writer, path)) = current {
+            writer.finish().at(&path)?;
+            stats.shards += 1;
+        }
+        stats.duplicates = pushed - stats.records;
+
+        let manifest_path = self.target.join(MANIFEST_FILE);
+        if !manifest_path.exists() {
+            let manifest = Manifest {
+                codec: ShardCodec::Zstd,
+                delimiter: Some(self.delimiter()),
+                ..self.manifest.clone()
+            };
+            fs::create_dir_all(&self.target).at(&self.target)?;
+            let text = toml::to_string(&manifest).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err));
+            fs::write(&manifest_path, text.at(&manifest_path)?).at(&manifest_path)?;
+        }
+        Ok(stats)
+    }
+
+    /// The sort entry for a raw line: the path of its shard, then the
+    /// normalised `email:password` record. `None` if it has no email.
+    fn entry(&self, line: Vec<u8>) -> Option<Vec<u8>> {
+        let (email, record) = normalize_record(&self.parser, line, self.delimiter())?;
+        let shard = self.manifest.key(&email).join("/");
+        let mut entry = Vec::with_capacity(shard.len() + record.len() + 1);
+        entry.extend_from_slice(shard.as_bytes());
+        entry.push(SHARD_SEPARATOR);
+        entry.extend_from_slice(&record);
+        Some(entry)
+    }
+
+    fn delimiter(&self) -> char {
+        self.manifest.delimiter.unwrap_or(':')
+    }
+
+    fn shard_path(&self, shard: &[u8]) -> PathBuf {
+        let mut path = self.target.join(Path::new(&*String::from_utf8_lossy(shard))).into_os_string();
+        path.push(".zst");
+        path.into()
+    }
+}
+
+/// Reads every line of `files`, plain, compressed or archived, in parallel
+/// and counted on `meter`, and pushes the sort entry `entry` makes of each
+/// to `sorter`, a batch at a time. Returns how many entries were pushed and
+/// how many lines `entry` rejected.
+pub(crate) fn sort_lines(
+    files: &[PathBuf],
+    meter: &Meter,
+    sorter: &ExternalSorter,
+    entry: impl Fn(Vec<u8>) -> Option<Vec<u8>> + Sync,
+) -> Result<(u64, u64), FileError> {
+    meter.add_files(files.len() as u64);
+    meter.expect_bytes(files.iter().map(|path| fs::metadata(path).map_or(0, |meta| meta.len())).sum());
+    let pushed = AtomicU64::new(0);
+    let rejected = AtomicU64::new(0);
+    files.par_iter().try_for_each(|path| {
+        let mut batch = Vec::with_capacity(SORT_BATCH);
+        let flush = |batch: &mut Vec<Vec<u8>>| -> io::Result<()> {
+            pushed.fetch_add(batch.len() as u64, Ordering::Relaxed);
+            sorter.push_batch(batch.drain(..))
+        };
+        let file = Metered::new(File::open(path).at(path)?, meter);
+        archive::for_each_member(file, |_, reader| {
+            let mut counter = LineCounter::new(meter);
+            for line in Lines::new(reader, Position::default()) {
+                let (_, line) = line?;
+                counter.tick();
+                match entry(line) {
+                    Some(entry) => batch.push(entry),
+                    None => {
+                        rejected.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                if batch.len() == SORT_BATCH {
+                    flush(&mut batch)?;
+                }
+            }
+            Ok(())
+        })
+        .and_then(|()| flush(&mut batch))
+        .at(path)
+    })?;
+    Ok((pushed.into_inner(), rejected.into_inner()))
+}
+
+/// A UTF-8 byte order mark, which dumps exported on Windows often start with.
+const BOM: &[u8] = "\u{feff}".as_bytes();
+
+/// Splits a raw line with `parser` into its normalised email and the
+/// `email:password` record it is stored as, with `delimiter` between the
+/// two. `None` if the line has no email address.
+///
+/// The record keeps the bytes read from the dump, so addresses that are
+/// not valid UTF-8 survive; only their ASCII letters are lowercased.
+pub(crate) fn normalize_record(parser: &RecordParser, mut line: Vec<u8>, delimiter: char) -> Option<(String, Vec<u8>)> {
+    if line.starts_with(BOM) {
+        line.drain(..BOM.len());
+    }
+    let record = parser.parse_bytes(line);
+    let email = normalize_email(record.email()?);
+    if !email.contains('@') {
+        return None;
+    }
+    let raw_email = &record.bytes()[record.raw_range(record.email_range()?)];
+    let raw_email = raw_email.strip_prefix(BOM).unwrap_or(raw_email).trim_ascii();
+    let password = match record.password_range() {
+        Some(range) => &record.bytes()[record.raw_range(range)],
+        None => &[],
+    };
+    let mut line = Vec::with_capacity(raw_email.len() + password.len() + 1);
+    line.extend(raw_email.iter().map(u8::to_ascii_lowercase));
+    line.extend_from_slice(delimiter.encode_utf8(&mut [0; 4]).as_bytes());
+    line.extend_from_slice(password);
+    Some((email, line))
+}
+
+/// An email address as shards are keyed by: without surrounding
+/// whitespace or a byte order mark, and lowercased.
+pub(crate) fn normalize_email(email: &str) -> String {
+    email.trim_start_matches('\u{feff}').trim().to_lowercase()
+}
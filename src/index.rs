@@ -0,0 +1,318 @@
+use crate::archive;
+use crate::block::{self, Block, Lines, Position, ScanUnit, Stamp};
+use crate::codec::Codec;
+use crate::dataset::Dataset;
+use crate::domain::DomainQuery;
+use crate::error::{AtPath, FileError};
+use crate::record::RecordParser;
+use crate::shard::{self, TEMP_SUFFIX};
+use indicatif::ProgressBar;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// File name of the domain index, stored at the dataset root.
+pub(crate) const INDEX_FILE: &str = ".domain-index.json";
+
+const INDEX_VERSION: u32 = 3;
+
+/// Maps each email domain to the blocks of the dataset that hold accounts
+/// at that domain, so domain searches only decode those blocks.
+///
+/// Multi-frame `.zst` files are indexed per frame, and get a frame sidecar
+/// for email lookups; every other file is a single block. Each file is
+/// recorded with its size and modification time so [`DomainIndex::build`]
+/// can reuse the entries of unchanged files, and searches fall back to
+/// scanning files that changed since the index was built.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DomainIndex {
+    version: u32,
+    shards: Vec<IndexedShard>,
+    /// Domain to `(shard, block)` positions in `shards`.
+    domains: BTreeMap<String, Vec<(u32, u32)>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct IndexedShard {
+    /// Path relative to the dataset root, `/`-separated.
+    path: String,
+    stamp: Stamp,
+    blocks: Vec<Block>,
+}
+
+/// What an index build did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IndexStats {
+    pub shards: usize,
+    /// Shards whose entries were carried over from the previous index.
+    pub reused: usize,
+    /// Shards that were new or changed and had to be decoded.
+    pub scanned: usize,
+    pub domains: usize,
+}
+
+impl DomainIndex {
+    /// Where the index of `dataset` lives.
+    pub fn path(dataset: &Dataset) -> PathBuf {
+        dataset.root().join(INDEX_FILE)
+    }
+
+    /// Loads the dataset's index, or `None` if it has not been built (or was
+    /// built by an incompatible version).
+    pub fn load(dataset: &Dataset) -> Result<Option<Self>, FileError> {
+        let path = Self::path(dataset);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).at(&path),
+        };
+        // An index from another version is treated as missing, so searches
+        // fall back to full scans until it is rebuilt.
+        match serde_json::from_reader::<_, DomainIndex>(BufReader::new(file)) {
+            Ok(index) if index.version == INDEX_VERSION => Ok(Some(index)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Writes the index next to the data, replacing any previous one
+    /// atomically.
+    pub fn save(&self, dataset: &Dataset) -> Result<(), FileError> {
+        let path = Self::path(dataset);
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(TEMP_SUFFIX);
+        let tmp = PathBuf::from(tmp);
+        let write = || -> io::Result<()> {
+            let mut writer = BufWriter::new(File::create(&tmp)?);
+            serde_json::to_writer(&mut writer, self)?;
+            writer.flush()
+        };
+        write().at(&tmp)?;
+        fs::rename(&tmp, &path).at(&path)
+    }
+
+    /// Indexes every file of `dataset`, reusing the entries of `previous`
+    /// for files whose size and modification time have not changed.
+    pub fn build(
+        dataset: &Dataset,
+        previous: Option<&DomainIndex>,
+        progress: Option<&ProgressBar>,
+    ) -> Result<(Self, IndexStats), FileError> {
+        let files = dataset.files()?;
+        let parser = dataset.manifest().configure(RecordParser::new());
+        if let Some(progress) = progress {
+            progress.set_length(files.len() as u64);
+        }
+
+        let previous_shards: HashMap<&str, usize> = previous
+            .map(|index| {
+                index
+                    .shards
+                    .iter()
+                    .enumerate()
+                    .map(|(id, shard)| (shard.path.as_str(), id))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let mut previous_domains: HashMap<usize, Vec<(&str, u32)>> = HashMap::new();
+        if let Some(index) = previous {
+            for (domain, refs) in &index.domains {
+                for &(shard, block) in refs {
+                    previous_domains
+                        .entry(shard as usize)
+                        .or_default()
+                        .push((domain.as_str(), block));
+                }
+            }
+        }
+
+        let indexed: Vec<(IndexedShard, Vec<BTreeSet<String>>, bool)> = files
+            .par_iter()
+            .map(|path| {
+                let relative = dataset.relative_path(path);
+                let stamp = Stamp::of(path).at(path)?;
+                let reusable = previous_shards.get(relative.as_str()).and_then(|&id| {
+                    let shard = &previous.unwrap().shards[id];
+                    (shard.stamp == stamp).then_some((id, shard))
+                });
+
+                let entry = match reusable {
+                    Some((id, shard)) => {
+                        let mut domains = vec![BTreeSet::new(); shard.blocks.len()];
+                        for (domain, block) in previous_domains.get(&id).into_iter().flatten() {
+                            domains[*block as usize].insert(domain.to_string());
+                        }
+                        (shard.clone(), domains, true)
+                    }
+                    None => {
+                        let (blocks, domains) = index_file(path, stamp, &parser).at(path)?;
+                        let shard = IndexedShard {
+                            path: relative,
+                            stamp,
+                            blocks,
+                        };
+                        (shard, domains, false)
+                    }
+                };
+                if let Some(progress) = progress {
+                    progress.inc(1);
+                }
+                Ok(entry)
+            })
+            .collect::<Result<_, FileError>>()?;
+
+        let mut index = DomainIndex {
+            version: INDEX_VERSION,
+            ..Default::default()
+        };
+        let mut stats = IndexStats::default();
+        for (id, (shard, block_domains, reused)) in indexed.into_iter().enumerate() {
+            for (block, domains) in block_domains.into_iter().enumerate() {
+                for domain in domains {
+                    index
+                        .domains
+                        .entry(domain)
+                        .or_default()
+                        .push((id as u32, block as u32));
+                }
+            }
+            index.shards.push(shard);
+            stats.shards += 1;
+            if reused {
+                stats.reused += 1;
+            } else {
+                stats.scanned += 1;
+            }
+        }
+        stats.domains = index.domains.len();
+        Ok((index, stats))
+    }
+
+    /// The units a search for `query` has to read among `files` of
+    /// `dataset`: the indexed blocks that hold the domain, plus the whole of
+    /// any file that is new or has changed since the index was built.
+    pub(crate) fn plan(
+        &self,
+        dataset: &Dataset,
+        files: Vec<PathBuf>,
+        query: &DomainQuery,
+    ) -> Result<Vec<ScanUnit>, FileError> {
+        let mut wanted: BTreeMap<u32, BTreeSet<u32>> = BTreeMap::new();
+        let mut add = |refs: &Vec<(u32, u32)>| {
+            for &(shard, block) in refs {
+                wanted.entry(shard).or_default().insert(block);
+            }
+        };
+        if query.includes_subdomains() {
+            self.domains
+                .iter()
+                .filter(|(domain, _)| query.matches_domain(domain))
+                .for_each(|(_, refs)| add(refs));
+        } else if let Some(refs) = self.domains.get(query.domain()) {
+            add(refs);
+        }
+
+        let shards: HashMap<&str, usize> = self
+            .shards
+            .iter()
+            .enumerate()
+            .map(|(id, shard)| (shard.path.as_str(), id))
+            .collect();
+
+        let mut units = Vec::new();
+        for path in files {
+            let relative = dataset.relative_path(&path);
+            let fresh = match shards.get(relative.as_str()) {
+                Some(&id) if self.shards[id].stamp == Stamp::of(&path).at(&path)? => Some(id),
+                _ => None,
+            };
+            let Some(id) = fresh else {
+                units.push(ScanUnit::file(path));
+                continue;
+            };
+
+            let shard = &self.shards[id];
+            let blocks = wanted.get(&(id as u32));
+            if shard.blocks.len() <= 1 {
+                if blocks.is_some() {
+                    units.push(ScanUnit::file(path));
+                }
+                continue;
+            }
+            for &block in blocks.into_iter().flatten() {
+                units.push(ScanUnit {
+                    path: path.clone(),
+                    block: Some(shard.blocks[block as usize]),
+                });
+            }
+        }
+        Ok(units)
+    }
+}
+
+/// Splits a file into blocks and collects the email domains in each,
+/// refreshing the frame sidecar of zstd shards along the way.
+fn index_file(path: &Path, stamp: Stamp, parser: &RecordParser) -> io::Result<(Vec<Block>, Vec<BTreeSet<String>>)> {
+    if Codec::of(path)? == Codec::Zstd && !archive::is_archive(path)? {
+        let summaries = block::zstd_frames(File::open(path)?, |frame| summarize(frame, parser))?;
+        let mut blocks = Vec::with_capacity(summaries.len());
+        let mut domains = Vec::with_capacity(summaries.len());
+        let mut frames = Vec::with_capacity(summaries.len());
+        let mut start = Position::default();
+        for (mut block, summary) in summaries {
+            block.start_line = start.lines;
+            block.start_offset = start.bytes;
+            start.lines += summary.end.lines;
+            start.bytes += summary.end.bytes;
+            domains.push(summary.domains);
+            frames.push((block, summary.first_line));
+            blocks.push(block);
+        }
+        shard::write_sidecar(path, stamp, frames)?;
+        return Ok((blocks, domains));
+    }
+
+    let mut domains = BTreeSet::new();
+    archive::for_each_member(File::open(path)?, |_, reader| {
+        domains.append(&mut summarize(reader, parser)?.domains);
+        Ok(())
+    })?;
+    let block = Block {
+        offset: 0,
+        len: stamp.size,
+        start_line: 0,
+        start_offset: 0,
+    };
+    Ok((vec![block], vec![domains]))
+}
+
+struct BlockSummary {
+    domains: BTreeSet<String>,
+    first_line: String,
+    /// The line and byte counts.
+    end: Position,
+}
+
+/// The email domains in `reader`, its first line and its size.
+fn summarize(reader: impl BufRead, parser: &RecordParser) -> io::Result<BlockSummary> {
+    let mut summary = BlockSummary {
+        domains: BTreeSet::new(),
+        first_line: String::new(),
+        end: Position::default(),
+    };
+    let mut lines = Lines::new(reader, Position::default());
+    for line in lines.by_ref() {
+        let (position, line) = line?;
+        let record = parser.parse_bytes(line);
+        if let Some(domain) = record.domain() {
+            summary.domains.insert(domain);
+        }
+        if position.lines == 0 {
+            summary.first_line = record.line().to_string();
+        }
+    }
+    summary.end = lines.position();
+    Ok(summary)
+}
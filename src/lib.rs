@@ -0,0 +1,47 @@
+//! Search engine behind the `breach_parser_rs` command line tool.
+//!
+//! A [`Dataset`] points at a directory of breach dumps (optionally `.gz` or
+//! `.zst` compressed, and sharded by the first characters of the email for
+//! direct lookups). A [`Searcher`] runs keyword scans across the whole
+//! dataset in parallel and direct email lookups against a single shard;
+//! what a scan looks for is described by a [`Matcher`]. Every hit carries a
+//! [`Record`] that splits the line into email, password and other fields.
+
+mod archive;
+mod block;
+mod codec;
+mod dataset;
+mod dedupe;
+mod domain;
+mod error;
+mod import;
+mod index;
+mod manifest;
+mod matcher;
+mod merge;
+mod output;
+mod query;
+mod record;
+mod redact;
+mod search;
+mod shard;
+mod sort;
+mod stats;
+
+pub use archive::MEMBER_SEPARATOR;
+pub use block::{Block, ScanUnit};
+pub use dataset::Dataset;
+pub use dedupe::{Dedupe, DedupedHits, GroupedAccount, GroupedAccounts, HitSpool, PasswordHits, SpooledAccounts};
+pub use domain::{Account, DomainQuery};
+pub use error::FileError;
+pub use import::{ImportStats, Importer};
+pub use index::{DomainIndex, IndexStats};
+pub use manifest::{KeyNormalization, Manifest, ShardCodec, SortOrder, MANIFEST_FILE};
+pub use matcher::{MatchMode, Matcher, MatcherBuilder, MatcherError};
+pub use merge::{MergeStats, Merger};
+pub use output::{Column, Format, HitRow, HitWriter};
+pub use query::{Query, QueryError, Term};
+pub use record::{InvalidUtf8, Layout, Record, RecordParser};
+pub use redact::Redaction;
+pub use search::{Hit, Searcher};
+pub use stats::ScanStats;
@@ -1,23 +1,98 @@
-use aho_corasick::AhoCorasick;
+use breach_parser_rs::{
+    Account, Column, Dataset, Dedupe, DomainIndex, DomainQuery, FileError, Format, Hit, HitSpool, HitWriter, Importer, Layout, MatchMode,
+    InvalidUtf8, MatcherBuilder, Merger, RecordParser, Redaction, ScanStats, Searcher,
+};
 use clap::{App, Arg};
-use flate2::read::GzDecoder;
-use indicatif::{ProgressBar, ProgressStyle};
-use rayon::prelude::*;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use indicatif::{HumanBytes, HumanCount, ProgressBar, ProgressStyle};
 use std::fs::File;
-use std::fs;
-use std::io::{self, BufRead, BufReader, Write};
-use std::path::Path;
-use walkdir::WalkDir;
-use flate2::read::MultiGzDecoder;
-use zstd::stream::read::Decoder as ZstdDecoder;
+use std::io::{self, BufRead, BufReader, BufWriter, IsTerminal, Write};
+use std::ops::ControlFlow;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+/// Hits buffered between the scanning workers and the writer thread. Workers
+/// block once it is full, so memory stays flat however many lines match.
+const HIT_CHANNEL_CAPACITY: usize = 4096;
+
+/// Exit status when a search ran but found nothing, as with `grep`.
+const EXIT_NO_MATCH: u8 = 1;
+/// Exit status when a search could not run or skipped unreadable files.
+const EXIT_ERROR: u8 = 2;
+
+/// Where results go, in the selected output format.
+type Output = HitWriter<BufWriter<Box<dyn Write + Send>>>;
 
 #[derive(Debug)]
 struct Config {
-    keyword: String,
-    keyword2: Option<String>,
+    keywords: Vec<String>,
+    regexes: Vec<String>,
+    query: Option<String>,
+    match_any: bool,
+    ignore_case: Option<bool>,
     output_file: String,
     breach_data_location: String,
+    /// Whether `--breach_data_location` was given rather than defaulted.
+    explicit_location: bool,
+    datasets: Vec<String>,
+    config_file: Option<String>,
     email: Option<String>,
+    emails_file: Option<String>,
+    domain: Option<DomainConfig>,
+    show: Option<ShowConfig>,
+    build_index: bool,
+    import: Option<ShardsConfig>,
+    merge: Option<ShardsConfig>,
+    layout: Layout,
+    format: Format,
+    columns: Option<Vec<Column>>,
+    header: bool,
+    redaction: Redaction,
+    invalid_utf8: InvalidUtf8,
+    strict: bool,
+    stats_json: Option<String>,
+    quiet: bool,
+    dedupe: Option<Dedupe>,
+    group_by_email: bool,
+    memory_mib: usize,
+    temp_dir: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+struct DomainConfig {
+    domain: String,
+    include_subdomains: bool,
+}
+
+#[derive(Debug)]
+struct ShowConfig {
+    dataset: Option<String>,
+    source: String,
+    line: u64,
+}
+
+/// What `import` or `merge` writes into the dataset at `into`.
+#[derive(Debug)]
+struct ShardsConfig {
+    /// The raw dumps to import, or the one batch dataset to merge.
+    sources: Vec<PathBuf>,
+    into: PathBuf,
+}
+
+/// The `--config` file, which names datasets to search together:
+///
+/// ```toml
+/// [datasets]
+/// comb = "/data/comb"
+/// internal = "/data/internal"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    datasets: BTreeMap<String, PathBuf>,
 }
 
 fn parse_arguments() -> Config {
@@ -25,166 +100,785 @@ fn parse_arguments() -> Config {
         .version("1.0")
         .author("Aazar")
         .about("Searches through breach data efficiently")
+        .subcommand_negates_reqs(true)
         .arg(Arg::new("keyword")
             .short('k')
             .long("keyword")
             .takes_value(true)
-            .required_unless_present("email")
-            .help("Primary keyword to search for"))
+            .multiple_occurrences(true)
+            .required_unless_present_any(["email", "emails_file", "regex", "query"])
+            .help("Keyword to search for (repeatable)"))
         .arg(Arg::new("second_keyword")
             .short('s')
             .long("second_keyword")
             .takes_value(true)
             .help("Secondary keyword to search for"))
+        .arg(Arg::new("regex")
+            .long("regex")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .help("Regular expression to search for (repeatable)"))
+        .arg(Arg::new("query")
+            .long("query")
+            .takes_value(true)
+            .help("Boolean query, e.g. 'corp.com AND NOT test OR \"acme corp\"'"))
+        .arg(Arg::new("any")
+            .long("any")
+            .help("Match lines satisfying any keyword, regex or query instead of all of them"))
+        .arg(Arg::new("ignore_case")
+            .short('i')
+            .long("ignore-case")
+            .help("Match keywords and regexes case-insensitively (default when a keyword contains '@')"))
+        .arg(Arg::new("case_sensitive")
+            .long("case-sensitive")
+            .conflicts_with("ignore_case")
+            .help("Match case-sensitively even for email-like keywords"))
         .arg(Arg::new("output_file")
             .short('o')
             .long("output_file")
             .takes_value(true)
             .default_value("print")
+            .global(true)
             .help("File to output results or 'print' to output to console"))
         .arg(Arg::new("email")
             .takes_value(true)
             .help("Email to search for directly"))
+        .arg(Arg::new("emails_file")
+            .long("emails-file")
+            .takes_value(true)
+            .conflicts_with_all(&["email", "dedupe", "group_by"])
+            .help("File of email addresses to look up, one per line, or '-' for stdin"))
+        .arg(Arg::new("format")
+            .long("format")
+            .takes_value(true)
+            .possible_values(["text", "jsonl", "csv", "tsv"])
+            .default_value("text")
+            .global(true)
+            .help("Output format: matching lines as text, JSON Lines, CSV or TSV"))
+        .arg(Arg::new("columns")
+            .long("columns")
+            .takes_value(true)
+            .global(true)
+            .validator(|columns| columns.split(',').try_for_each(|column| column.parse::<Column>().map(drop)))
+            .help("Comma-separated CSV/TSV columns: dataset, email, username, password, url, domain, extra, source, line_number, offset, patterns, line"))
+        .arg(Arg::new("no_header")
+            .long("no-header")
+            .global(true)
+            .help("Leave out the CSV/TSV header row"))
+        .arg(Arg::new("redact")
+            .long("redact")
+            .takes_value(true)
+            .possible_values(["none", "mask", "hash", "omit"])
+            .default_value("none")
+            .env("BREACH_PARSE_REDACT")
+            .global(true)
+            .help("How to show passwords: as found, masked, hashed with SHA-256 (HMAC with --hmac-key) or omitted"))
+        .arg(Arg::new("hmac_key")
+            .long("hmac-key")
+            .takes_value(true)
+            .env("BREACH_PARSE_HMAC_KEY")
+            .hide_env_values(true)
+            .global(true)
+            .help("Key for HMAC-SHA-256 password hashes with --redact hash"))
+        .arg(Arg::new("invalid_utf8")
+            .long("invalid-utf8")
+            .takes_value(true)
+            .possible_values(["lossy", "hex", "raw"])
+            .default_value("lossy")
+            .global(true)
+            .help("How to write bytes that are not valid UTF-8: replaced with U+FFFD, as \\xNN escapes, or raw in matching lines"))
+        .arg(Arg::new("strict")
+            .long("strict")
+            .global(true)
+            .help("Stop at the first unreadable or corrupt file instead of skipping it"))
+        .arg(Arg::new("quiet")
+            .short('q')
+            .long("quiet")
+            .global(true)
+            .help("Print nothing but results: no progress bar, statistics or status messages"))
+        .arg(Arg::new("stats_json")
+            .long("stats-json")
+            .takes_value(true)
+            .value_name("FILE")
+            .global(true)
+            .help("Also write the end-of-run statistics to FILE as JSON"))
+        .arg(Arg::new("dedupe")
+            .long("dedupe")
+            .takes_value(true)
+            .min_values(0)
+            .require_equals(true)
+            .default_missing_value("exact")
+            .value_name("MODE")
+            .possible_values(["exact", "email"])
+            .global(true)
+            .help("Drop repeated hits: identical lines (exact, the default) or the same lowercased email and password (email)"))
+        .arg(Arg::new("group_by")
+            .long("group-by")
+            .takes_value(true)
+            .possible_values(["email"])
+            .conflicts_with_all(&["dedupe", "columns"])
+            .global(true)
+            .help("Collapse hits into one entry per account, listing its distinct passwords and where each was seen"))
+        .arg(Arg::new("memory")
+            .long("memory")
+            .takes_value(true)
+            .value_name("MIB")
+            .default_value("512")
+            .validator(|memory| memory.parse::<usize>().map(drop))
+            .global(true)
+            .help("Memory for sorting in import, merge, --dedupe and --group-by, in MiB, before sorted runs are spilled to disk"))
+        .arg(Arg::new("temp_dir")
+            .long("temp-dir")
+            .takes_value(true)
+            .global(true)
+            .help("Directory for sorted runs, instead of the system's temporary directory"))
+        .arg(Arg::new("layout")
+            .long("layout")
+            .takes_value(true)
+            .possible_values(["auto", "email-pass", "user-pass", "url-email-pass", "tsv"])
+            .default_value("auto")
+            .global(true)
+            .help("How the fields of each line are laid out"))
         .arg(Arg::new("breach_data_location")
             .long("breach_data_location")
             .takes_value(true)
             .default_value("data.tmp")
+            .global(true)
             .help("Location of breach data"))
+        .arg(Arg::new("dataset")
+            .long("dataset")
+            .takes_value(true)
+            .value_name("NAME=PATH")
+            .multiple_occurrences(true)
+            .global(true)
+            .help("Dataset to search, named NAME (repeatable; replaces the default --breach_data_location)"))
+        .arg(Arg::new("config")
+            .long("config")
+            .takes_value(true)
+            .value_name("FILE")
+            .env("BREACH_PARSE_CONFIG")
+            .global(true)
+            .help("TOML file naming datasets to search, under [datasets]"))
+        .subcommand(App::new("domain")
+            .about("Lists every account whose email address is at a domain")
+            .arg(Arg::new("domain")
+                .required(true)
+                .takes_value(true)
+                .help("Domain to search for, e.g. example.com"))
+            .arg(Arg::new("include_subdomains")
+                .long("include-subdomains")
+                .help("Also match addresses at subdomains, e.g. mail.example.com")))
+        .subcommand(App::new("show")
+            .about("Prints the record at a source file and line number reported by a search")
+            .arg(Arg::new("source")
+                .long("source")
+                .required(true)
+                .takes_value(true)
+                .help("File the record came from, relative to the breach data location"))
+            .arg(Arg::new("dataset_name")
+                .long("dataset-name")
+                .takes_value(true)
+                .help("Dataset the record came from, when searching several"))
+            .arg(Arg::new("line")
+                .long("line")
+                .required(true)
+                .takes_value(true)
+                .validator(|line| line.parse::<u64>().map(drop))
+                .help("1-based line number of the record")))
+        .subcommand(App::new("index")
+            .about("Manages the domain index stored next to the data")
+            .subcommand_required(true)
+            .subcommand(App::new("build")
+                .about("Builds or incrementally refreshes the domain index")))
+        .subcommand(App::new("import")
+            .about("Normalises raw dumps into sorted, deduplicated .zst email shards")
+            .arg(Arg::new("files")
+                .required(true)
+                .takes_value(true)
+                .multiple_values(true)
+                .help("Raw dump files, archives or directories of them"))
+            .arg(Arg::new("into")
+                .long("into")
+                .required(true)
+                .takes_value(true)
+                .help("Dataset directory to write the shards to")))
+
+        .subcommand(App::new("merge")
+            .about("Merges a batch of new records, such as an import, into the sorted shards of a dataset")
+            .arg(Arg::new("batch")
+                .required(true)
+                .takes_value(true)
+                .help("Dataset directory holding the new records"))
+            .arg(Arg::new("into")
+                .long("into")
+                .required(true)
+                .takes_value(true)
+                .help("Dataset directory whose shards the records are merged into")))
+
         .get_matches();
 
-    let config = Config {
-        keyword: matches.value_of("keyword").unwrap_or_default().to_string(),
-        keyword2: matches.value_of("keyword2").map(|s| s.to_string()),
+    Config {
+        keywords: matches.values_of("keyword").into_iter().flatten()
+            .chain(matches.value_of("second_keyword"))
+            .map(|s| s.to_string())
+            .collect(),
+        regexes: matches.values_of("regex").into_iter().flatten().map(|s| s.to_string()).collect(),
+        query: matches.value_of("query").map(|s| s.to_string()),
+        match_any: matches.is_present("any"),
+        ignore_case: if matches.is_present("ignore_case") {
+            Some(true)
+        } else if matches.is_present("case_sensitive") {
+            Some(false)
+        } else {
+            None
+        },
         output_file: matches.value_of("output_file").unwrap_or("print").to_string(),
         breach_data_location: matches.value_of("breach_data_location").unwrap().to_string(),
+        explicit_location: matches.occurrences_of("breach_data_location") > 0,
+        datasets: matches.values_of("dataset").into_iter().flatten().map(String::from).collect(),
+        config_file: matches.value_of("config").map(String::from),
         email: matches.value_of("email").map(|s| s.to_string()),
-    };
+        emails_file: matches.value_of("emails_file").map(|s| s.to_string()),
+        domain: matches.subcommand_matches("domain").map(|domain| DomainConfig {
+            domain: domain.value_of("domain").unwrap().to_string(),
+            include_subdomains: domain.is_present("include_subdomains"),
+        }),
+        show: matches.subcommand_matches("show").map(|show| ShowConfig {
+            dataset: show.value_of("dataset_name").map(String::from),
+            source: show.value_of("source").unwrap().to_string(),
+            line: show.value_of("line").unwrap().parse().unwrap(),
+        }),
+        build_index: matches
+            .subcommand_matches("index")
+            .and_then(|index| index.subcommand_matches("build"))
+            .is_some(),
+        import: matches.subcommand_matches("import").map(|import| ShardsConfig {
+            sources: import.values_of("files").into_iter().flatten().map(PathBuf::from).collect(),
+            into: PathBuf::from(import.value_of("into").unwrap()),
+        }),
+        merge: matches.subcommand_matches("merge").map(|merge| ShardsConfig {
+            sources: vec![PathBuf::from(merge.value_of("batch").unwrap())],
+            into: PathBuf::from(merge.value_of("into").unwrap()),
+        }),
+        layout: match matches.value_of("layout").unwrap() {
+            "email-pass" => Layout::EmailPass,
+            "user-pass" => Layout::UserPass,
+            "url-email-pass" => Layout::UrlEmailPass,
+            "tsv" => Layout::Tsv,
+            _ => Layout::Auto,
+        },
+        format: match matches.value_of("format").unwrap() {
+            "jsonl" => Format::JsonLines,
+            "csv" => Format::Csv,
+            "tsv" => Format::Tsv,
+            _ => Format::Text,
+        },
+        columns: matches
+            .value_of("columns")
+            .map(|columns| columns.split(',').map(|column| column.parse().unwrap()).collect()),
+        header: !matches.is_present("no_header"),
+        redaction: match matches.value_of("redact").unwrap().parse().unwrap() {
+            Redaction::Hash { .. } => Redaction::Hash {
+                key: matches.value_of("hmac_key").map(|key| key.as_bytes().to_vec()),
+            },
+            redaction => redaction,
+        },
+        invalid_utf8: match matches.value_of("invalid_utf8").unwrap() {
+            "hex" => InvalidUtf8::Hex,
+            "raw" => InvalidUtf8::Raw,
+            _ => InvalidUtf8::Lossy,
+        },
+        strict: matches.is_present("strict"),
+        stats_json: matches.value_of("stats_json").map(String::from),
+        quiet: matches.is_present("quiet"),
+        dedupe: matches.value_of("dedupe").map(|dedupe| dedupe.parse().unwrap()),
+        group_by_email: matches.value_of("group_by") == Some("email"),
+        memory_mib: matches.value_of("memory").unwrap().parse().unwrap(),
+        temp_dir: matches.value_of("temp_dir").map(PathBuf::from),
+    }
+}
 
-    config
+fn main() -> ExitCode {
+    let config = parse_arguments();
+    match run(&config) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            ExitCode::from(EXIT_ERROR)
+        }
+    }
 }
 
-fn process_email(keyword: &str, base_dir: &str) -> Vec<String> {
-    let keyword_lower = keyword.to_lowercase();
-    let chars: Vec<char> = keyword_lower.chars().collect();
-    let mut path = format!("{}", base_dir);
-    for (i, c) in chars.iter().enumerate().take(3) {
-        path.push('/');
-        if c.is_alphanumeric() {
-            path.push(*c);
-        } else {
-            path.push_str("symbols");
-            break;
+/// Runs the command `config` describes. Only results go to stdout; the
+/// progress bar, statistics and every other message go to stderr.
+fn run(config: &Config) -> io::Result<ExitCode> {
+    if let Some(import) = &config.import {
+        import_dumps(import, config)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    if let Some(merge) = &config.merge {
+        merge_batch(merge, config)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let datasets = match open_datasets(config) {
+        Ok(datasets) => datasets,
+        Err(err) => {
+            eprintln!("{}", err);
+            return Ok(ExitCode::from(EXIT_ERROR));
         }
+    };
+    let parser = RecordParser::new().layout(config.layout).invalid_utf8(config.invalid_utf8);
+    let searcher = Searcher::across(datasets).with_parser(parser).strict(config.strict);
 
-        if i < 2 {
-            let gz_path = format!("{}.gz", path);
-            let zst_path = format!("{}.zst", path);
-            if fs::metadata(&gz_path).is_ok() {
-                path = gz_path;
-                break;
-            } else if fs::metadata(&zst_path).is_ok() {
-                path = zst_path;
-                break;
-            }
+    if let Some(email) = &config.email {
+        let hits = searcher.lookup_email(email)?;
+        results_end(write_all_hits(hits, &searcher, config))?;
+        return report(&searcher, config);
+    }
+
+    if let Some(emails_file) = &config.emails_file {
+        let emails: Vec<String> = if emails_file == "-" {
+            io::stdin().lock().lines().collect::<io::Result<_>>()?
         } else {
-            if fs::metadata(&format!("{}.gz", path)).is_ok() {
-                path.push_str(".gz");
-            } else {
-                path.push_str(".zst");
-            }
+            BufReader::new(File::open(emails_file)?).lines().collect::<io::Result<_>>()?
+        };
+        let accounts = searcher.lookup_emails(&emails)?;
+        results_end(write_accounts(&accounts, open_writer(config, &searcher)?, config))?;
+        return report(&searcher, config);
+    }
+
+    if let Some(show) = &config.show {
+        let Some(hit) = searcher.record_at(show.dataset.as_deref(), &show.source, show.line)? else {
+            eprintln!("{} has no line {}", show.source, show.line);
+            return Ok(ExitCode::from(EXIT_NO_MATCH));
+        };
+        let mut writer = open_writer(config, &searcher)?;
+        results_end(writer.write_hit(&hit).and_then(|()| writer.finish()))?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if config.build_index {
+        for dataset in searcher.datasets() {
+            build_index(dataset, config)?;
         }
+        return Ok(ExitCode::SUCCESS);
     }
-    
-    let file = File::open(&path).expect("Unable to open file");
-    let buf_reader = if path.ends_with(".gz") {
-        Box::new(BufReader::new(MultiGzDecoder::new(file))) as Box<dyn BufRead>
-    } else {
-        Box::new(BufReader::new(ZstdDecoder::new(file).unwrap())) as Box<dyn BufRead>
+
+    if let Some(domain) = &config.domain {
+        let query = DomainQuery::new(&domain.domain).include_subdomains(domain.include_subdomains);
+        let progress_bar = progress_bar(config, BYTES_PROGRESS);
+        let output = open_writer(config, &searcher)?;
+        // The per-account report is spooled too, so that accounts come out
+        // in order without holding every hit in memory.
+        let (spool, by_account) = match new_spool(config) {
+            Some(spool) => (spool, false),
+            None => (spool_for(config, Dedupe::Email), true),
+        };
+
+        let searcher = searcher.with_progress(progress_bar.clone());
+        let spooled = stream_hits(Some(spool), output, |sink| searcher.stream_domain(&query, sink))?;
+        progress_bar.finish_and_clear();
+        if let Some((spool, output)) = spooled {
+            results_end(match by_account {
+                true => write_spooled_accounts(spool, &searcher, config, output),
+                false => write_spooled(spool, &searcher, config, output),
+            })?;
+        }
+        return report(&searcher, config);
+    }
+
+    let mut builder = MatcherBuilder::new().mode(if config.match_any { MatchMode::Any } else { MatchMode::All });
+    if let Some(ignore_case) = config.ignore_case {
+        builder = builder.ignore_case(ignore_case);
+    }
+    for keyword in &config.keywords {
+        builder = builder.keyword(keyword);
+    }
+    for regex in &config.regexes {
+        builder = builder.regex(regex);
+    }
+    if let Some(query) = &config.query {
+        builder = builder.query(query);
+    }
+    let matcher = match builder.build() {
+        Ok(matcher) => matcher,
+        Err(err) => {
+            eprintln!("{}", err);
+            return Ok(ExitCode::from(EXIT_ERROR));
+        }
     };
-    
-    buf_reader.lines()
-        .filter_map(Result::ok)
-        .filter(|line| line.to_lowercase().starts_with(&keyword_lower))
-        .collect()
-}
-
-fn process_file(path: &Path, ac: &AhoCorasick) -> Vec<String> {
-    let file = File::open(path).expect("Unable to open file");
-    let reader: Box<dyn BufRead> = if path.extension().and_then(|s| s.to_str()) == Some("gz") {
-        Box::new(BufReader::new(GzDecoder::new(file)))
-    } else if path.extension().and_then(|s| s.to_str()) == Some("zst") {
-        Box::new(BufReader::new(ZstdDecoder::new(file).unwrap()))
+
+    let progress_bar = progress_bar(config, BYTES_PROGRESS);
+
+    let output = open_writer(config, &searcher)?;
+    let spool = new_spool(config);
+
+    let searcher = searcher.with_progress(progress_bar.clone());
+    let spooled = stream_hits(spool, output, |sink| searcher.stream(&matcher, sink))?;
+
+    progress_bar.finish_and_clear();
+    if let Some((spool, output)) = spooled {
+        results_end(write_spooled(spool, &searcher, config, output))?;
+    }
+    if !config.quiet && config.output_file != "print" {
+        eprintln!("Results written to {}", config.output_file);
+    }
+
+    report(&searcher, config)
+}
+
+fn build_index(dataset: &Dataset, config: &Config) -> io::Result<()> {
+    let previous = DomainIndex::load(dataset)?;
+    let progress_bar = progress_bar(config, FILES_PROGRESS);
+
+    let (index, stats) = DomainIndex::build(dataset, previous.as_ref(), Some(&progress_bar))?;
+    index.save(dataset)?;
+    progress_bar.finish_and_clear();
+
+    if config.quiet {
+        return Ok(());
+    }
+    eprintln!(
+        "Indexed {} domains across {} files ({} rescanned, {} unchanged) into {}",
+        stats.domains,
+        stats.shards,
+        stats.scanned,
+        stats.reused,
+        DomainIndex::path(dataset).display()
+    );
+    Ok(())
+}
+
+/// Progress of reading compressed bytes, for searches, imports and merges.
+const BYTES_PROGRESS: &str =
+    "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({binary_bytes_per_sec}, {msg}) ({eta})";
+
+/// Progress of indexing files.
+const FILES_PROGRESS: &str = "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos:>7}/{len:7} ({eta})";
+
+/// A progress bar drawn on stderr with `template`, or a hidden one with
+/// `--quiet` or when stderr is not a terminal, so logs and pipes stay
+/// clean.
+fn progress_bar(config: &Config, template: &str) -> ProgressBar {
+    if config.quiet || !io::stderr().is_terminal() {
+        return ProgressBar::hidden();
+    }
+    ProgressBar::new(0).with_style(ProgressStyle::default_bar().template(template).unwrap().progress_chars("#>-"))
+}
+
+/// Ends a search: lists the files it skipped, prints its statistics and,
+/// with `--stats-json`, saves them. Returns the exit code: success if
+/// anything matched, [`EXIT_NO_MATCH`] if nothing did, and [`EXIT_ERROR`]
+/// if files were skipped, as `grep` does.
+fn report(searcher: &Searcher, config: &Config) -> io::Result<ExitCode> {
+    let skipped = report_failures(searcher);
+    let stats = searcher.stats();
+    if !config.quiet {
+        report_stats(&stats);
+    }
+    if let Some(path) = &config.stats_json {
+        let mut file = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(&mut file, &stats)?;
+        writeln!(file)?;
+        file.flush()?;
+    }
+    Ok(if skipped {
+        ExitCode::from(EXIT_ERROR)
+    } else if stats.hits == 0 {
+        ExitCode::from(EXIT_NO_MATCH)
     } else {
-        Box::new(BufReader::new(file))
+        ExitCode::SUCCESS
+    })
+}
+
+/// Prints what a search read and found, on stderr.
+fn report_stats(stats: &ScanStats) {
+    eprintln!("\nStatistics:");
+    eprintln!("    Files:    {}", HumanCount(stats.files));
+    eprintln!("    Bytes:    {} ({}/s)", HumanBytes(stats.bytes), HumanBytes(stats.bytes_per_sec() as u64));
+    eprintln!("    Lines:    {} ({}/s)", HumanCount(stats.lines), HumanCount(stats.lines_per_sec() as u64));
+    eprintln!("    Hits:     {}", HumanCount(stats.hits));
+    eprintln!("    Skipped:  {}", HumanCount(stats.skipped));
+    eprintln!("    Elapsed:  {:.2}s", stats.elapsed_secs);
+}
+
+fn import_dumps(import: &ShardsConfig, config: &Config) -> io::Result<()> {
+    let progress_bar = progress_bar(config, BYTES_PROGRESS);
+
+    let mut importer = Importer::new(&import.into)?
+        .with_parser(RecordParser::new().layout(config.layout))
+        .with_memory_limit(config.memory_mib << 20)
+        .with_progress(progress_bar.clone());
+    if let Some(temp_dir) = &config.temp_dir {
+        importer = importer.with_temp_dir(temp_dir);
+    }
+    let stats = importer.import(&import.sources)?;
+    progress_bar.finish_and_clear();
+
+    if config.quiet {
+        return Ok(());
+    }
+    eprintln!(
+        "Imported {} records into {} shards in {} from {} lines in {} files ({} duplicates dropped, {} lines without an email skipped)",
+        HumanCount(stats.records),
+        HumanCount(stats.shards),
+        import.into.display(),
+        HumanCount(stats.lines),
+        HumanCount(stats.files),
+        HumanCount(stats.duplicates),
+        HumanCount(stats.rejected),
+    );
+    Ok(())
+}
+
+fn merge_batch(merge: &ShardsConfig, config: &Config) -> io::Result<()> {
+    let progress_bar = progress_bar(config, BYTES_PROGRESS);
+
+    let batch = Dataset::open(&merge.sources[0])?;
+    let mut merger = Merger::new(Dataset::open(&merge.into)?)?
+        .with_memory_limit(config.memory_mib << 20)
+        .with_progress(progress_bar.clone());
+    if let Some(temp_dir) = &config.temp_dir {
+        merger = merger.with_temp_dir(temp_dir);
+    }
+    let stats = merger.merge(&batch)?;
+    progress_bar.finish_and_clear();
+
+    if config.quiet {
+        return Ok(());
+    }
+    eprintln!(
+        "Merged {} new records into {} shards of {} from {} lines in {} files ({} duplicates dropped, {} lines without an email skipped)",
+        HumanCount(stats.added),
+        HumanCount(stats.shards),
+        merge.into.display(),
+        HumanCount(stats.lines),
+        HumanCount(stats.files),
+        HumanCount(stats.duplicates),
+        HumanCount(stats.rejected),
+    );
+    Ok(())
+}
+
+/// Lists the files a search skipped because they could not be read, and
+/// returns whether there were any.
+fn report_failures(searcher: &Searcher) -> bool {
+    let failures = searcher.take_failures();
+    if failures.is_empty() {
+        return false;
+    }
+    let plural = if failures.len() == 1 { "" } else { "s" };
+    eprintln!("\nSkipped {} unreadable file{} (use --strict to stop at the first):", failures.len(), plural);
+    for failure in failures {
+        eprintln!("    {}", failure);
+    }
+    true
+}
+
+/// Opens the datasets named by `--config` and `--dataset`, and the one at
+/// `--breach_data_location` if it was given or nothing else was.
+fn open_datasets(config: &Config) -> io::Result<Vec<Dataset>> {
+    let mut named: Vec<(Option<String>, PathBuf)> = Vec::new();
+    if let Some(path) = &config.config_file {
+        let text = std::fs::read_to_string(path)?;
+        let file: ConfigFile = toml::from_str(&text)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, err.message())))?;
+        named.extend(file.datasets.into_iter().map(|(name, root)| (Some(name), root)));
+    }
+    for dataset in &config.datasets {
+        named.push(match dataset.split_once('=') {
+            Some((name, root)) => (Some(name.to_string()), PathBuf::from(root)),
+            None => (None, PathBuf::from(dataset)),
+        });
+    }
+    if config.explicit_location || named.is_empty() {
+        named.push((None, PathBuf::from(&config.breach_data_location)));
+    }
+
+    let mut datasets: Vec<Dataset> = Vec::new();
+    for (name, root) in named {
+        let dataset = Dataset::open(root)?;
+        let dataset = match name {
+            Some(name) => dataset.with_name(name),
+            None => dataset,
+        };
+        if datasets.iter().any(|other| other.name() == dataset.name()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Two datasets are named '{}'", dataset.name()),
+            ));
+        }
+        datasets.push(dataset);
+    }
+    Ok(datasets)
+}
+
+/// Opens `-o`: a file, or stdout for `print`.
+fn open_output(output_file: &str) -> io::Result<Box<dyn Write + Send>> {
+    Ok(match output_file {
+        "print" => Box::new(io::stdout()),
+        _ => Box::new(File::create(output_file)?),
+    })
+}
+
+/// Opens `-o` wrapped in a [`HitWriter`] set up from the output flags.
+/// Searches across several datasets name the dataset of each hit.
+fn open_writer(config: &Config, searcher: &Searcher) -> io::Result<Output> {
+    let several = searcher.datasets().len() > 1;
+    let columns = match &config.columns {
+        Some(columns) => columns.clone(),
+        None if several => [&[Column::Dataset], Column::DEFAULT].concat(),
+        None => Column::DEFAULT.to_vec(),
     };
-    reader
-        .lines()
-        .filter_map(Result::ok)
-        .filter(|line| ac.find_iter(line).count() == ac.pattern_count())
-        .collect()
+    Ok(HitWriter::new(BufWriter::new(open_output(&config.output_file)?), config.format)
+        .with_columns(columns)
+        .with_dataset_names(several)
+        .with_header(config.header)
+        .with_redaction(config.redaction.clone())
+        .with_invalid_utf8(config.invalid_utf8))
 }
 
-fn main() -> io::Result<()> {
-    let config = parse_arguments();
+/// Writes `hits`, without copies or grouped by account if asked to.
+fn write_all_hits(hits: Vec<Hit>, searcher: &Searcher, config: &Config) -> io::Result<()> {
+    let mut writer = open_writer(config, searcher)?;
+    if let Some(mut spool) = new_spool(config) {
+        hits.iter().try_for_each(|hit| spool.push(hit))?;
+        return write_spooled(spool, searcher, config, writer);
+    }
+    for hit in hits {
+        writer.write_hit(&hit)?;
+    }
+    writer.finish()
+}
+
+/// Treats the reader of the results going away, as with `| head`, as the
+/// normal end of the results rather than an error.
+fn results_end(written: io::Result<()>) -> io::Result<()> {
+    match written {
+        Err(err) if err.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        written => written,
+    }
+}
+
+/// Writes a per-account report: each account with the entries found for it.
+fn write_accounts(accounts: &[Account], mut writer: Output, config: &Config) -> io::Result<()> {
+    for account in accounts {
+        writer.write_account(account)?;
+    }
+    finish_accounts(writer, config)
+}
 
-    if !Path::new(&config.breach_data_location).is_dir() {
-        println!("Could not find a directory at {}", config.breach_data_location);
-        std::process::exit(1);
+/// Ends an account report, noting how many accounts and entries it listed
+/// on stderr after text reports, apart from the results themselves.
+fn finish_accounts(mut writer: Output, config: &Config) -> io::Result<()> {
+    writer.flush()?;
+    if config.format == Format::Text && writer.accounts() > 0 && !config.quiet {
+        eprintln!("\n{} accounts, {} entries", writer.accounts(), writer.entries());
     }
+    writer.finish()
+}
+
+/// The spool `--dedupe` and `--group-by` collect hits in, if either is
+/// set.
+fn new_spool(config: &Config) -> Option<HitSpool> {
+    let dedupe = if config.group_by_email { Dedupe::Email } else { config.dedupe? };
+    Some(spool_for(config, dedupe))
+}
 
-    if let Some(email) = config.email {
-        let results = process_email(&email, &config.breach_data_location);
-        for line in results {
-            println!("{}", line);
+/// A spool ordering hits by `dedupe`, set up from the spool flags.
+fn spool_for(config: &Config, dedupe: Dedupe) -> HitSpool {
+    let spool = HitSpool::new(dedupe)
+        .with_redaction(config.redaction.clone())
+        .with_memory_limit(config.memory_mib << 20);
+    match &config.temp_dir {
+        Some(temp_dir) => spool.with_temp_dir(temp_dir),
+        None => spool,
+    }
+}
+
+/// Collects hits from the scanning workers in `spool` until the scan ends.
+fn spool_hits(rx: Receiver<Hit>, mut spool: HitSpool) -> io::Result<HitSpool> {
+    for hit in rx {
+        spool.push(&hit)?;
+    }
+    Ok(spool)
+}
+
+/// Writes the hits collected in `spool`, grouped by account with
+/// `--group-by email` and without copies otherwise. They come back from
+/// the spool already redacted.
+fn write_spooled(spool: HitSpool, searcher: &Searcher, config: &Config, output: Output) -> io::Result<()> {
+    let mut output = output
+        .with_redaction(config.redaction.reapplied())
+        .with_groups(config.group_by_email);
+    if config.group_by_email {
+        for account in spool.group(searcher)? {
+            output.write_group(&account?)?;
+        }
+    } else {
+        for hit in spool.dedupe(searcher)? {
+            output.write_hit(&hit?)?;
         }
-        return Ok(())
-    }
-
-    let mut patterns = vec![config.keyword];
-    if let Some(keyword2) = config.keyword2 {
-        patterns.push(keyword2);
-    }
-    let ac = AhoCorasick::new(&patterns);
-
-    let files: Vec<_> = WalkDir::new(&config.breach_data_location)
-        .into_iter()
-        .filter_map(|e| e.ok())
-        .filter(|e| e.path().is_file())
-        .collect();
-
-    let progress_bar = ProgressBar::new(files.len() as u64);
-    progress_bar.set_style(ProgressStyle::default_bar()
-        .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos:>7}/{len:7} ({eta})")
-        .unwrap()
-        .progress_chars("#>-"));
-
-    let results: Vec<_> = files.into_par_iter()
-        .map(|entry| {
-            progress_bar.inc(1);
-            process_file(entry.path(), &ac)
-        })
-        .reduce(Vec::new, |mut a, b| {
-            a.extend(b);
-            a
+    }
+    finish_accounts(output, config)
+}
+
+/// Runs `scan` with a sink sending its hits to a writer thread, which
+/// collects them in `spool` if there is one and otherwise writes them to
+/// `output` as they arrive. Returns the spool to write out, unless the
+/// reader of the results went away.
+fn stream_hits<S>(spool: Option<HitSpool>, output: Output, scan: S) -> io::Result<Option<(HitSpool, Output)>>
+where
+    S: FnOnce(&(dyn Fn(Hit) -> ControlFlow<()> + Sync)) -> Result<(), FileError>,
+{
+    let (tx, rx) = mpsc::sync_channel(HIT_CHANNEL_CAPACITY);
+    thread::scope(|scope| {
+        let writer = scope.spawn(move || match spool {
+            Some(spool) => spool_hits(rx, spool).map(|spool| Some((spool, output))),
+            None => write_hits(rx, output).map(|()| None),
+        });
+        let scanned = scan(&|hit| match tx.send(hit) {
+            Ok(()) => ControlFlow::Continue(()),
+            // The writer only hangs up after an I/O error, reported below.
+            Err(_) => ControlFlow::Break(()),
         });
+        drop(tx);
+        let spooled = match writer.join().expect("writer thread panicked") {
+            Err(err) if err.kind() == io::ErrorKind::BrokenPipe => None,
+            spooled => spooled?,
+        };
+        scanned.map_err(io::Error::from)?;
+        Ok(spooled)
+    })
+}
 
-    progress_bar.finish_with_message("Processing complete.");
+/// Writes a per-account report of the hits collected in `spool`, which
+/// orders them by [`Dedupe::Email`].
+fn write_spooled_accounts(spool: HitSpool, searcher: &Searcher, config: &Config, output: Output) -> io::Result<()> {
+    let mut output = output.with_redaction(config.redaction.reapplied());
+    for account in spool.accounts(searcher)? {
+        output.write_account(&account?)?;
+    }
+    finish_accounts(output, config)
+}
 
-    match config.output_file.as_ref() {
-        "print" => {
-            println!("\nResults:\n");
-            for line in results {
-                println!("{}", line);
-            }
-        },
-        _ => {
-            let mut file = File::create(&config.output_file)?;
-            for line in results {
-                writeln!(file, "{}", line)?;
+/// Drains hits from the scanning workers into `output` as they arrive,
+/// flushing whenever the workers fall behind so results show up promptly.
+fn write_hits(rx: Receiver<Hit>, mut output: Output) -> io::Result<()> {
+    loop {
+        let hit = match rx.try_recv() {
+            Ok(hit) => hit,
+            Err(TryRecvError::Empty) => {
+                output.flush()?;
+                match rx.recv() {
+                    Ok(hit) => hit,
+                    Err(_) => break,
+                }
             }
-            println!("Results written to {}", config.output_file);
-        },
+            Err(TryRecvError::Disconnected) => break,
+        };
+        output.write_hit(&hit)?;
     }
-
-    Ok(())
-}
\ No newline at end of file
+    output.finish()
+}
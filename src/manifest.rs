@@ -0,0 +1,190 @@
+use crate::codec::Codec;
+use crate::record::RecordParser;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Name of the manifest at the root of a dataset.
+pub const MANIFEST_FILE: &str = "dataset.toml";
+
+/// Extensions a shard may have when the manifest does not name its codec.
+const SHARD_EXTENSIONS: [&str; 6] = ["", ".gz", ".zst", ".xz", ".bz2", ".lz4"];
+
+/// How a dataset's email shards are laid out, read from the
+/// `dataset.toml` at its root:
+///
+/// ```toml
+/// name = "collection-1"
+/// depth = 3
+/// normalize = "lowercase"
+/// codec = "zstd"
+/// delimiter = ":"
+/// sort = "lowercase"
+/// ```
+///
+/// A dataset without one gets the defaults, which describe the classic
+/// `root/a/b/c.{gz,zst}` layout.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Manifest {
+    /// Name of the dataset in hit sources; the root directory's name if
+    /// not set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// How many leading characters of an address route it to its shard,
+    /// one directory level per character with the last naming the file.
+    pub depth: usize,
+    /// How an address is turned into the key its shard is named after.
+    pub normalize: KeyNormalization,
+    /// How the shards are compressed, which sets their file extension.
+    pub codec: ShardCodec,
+    /// The field delimiter of the lines; `:` and `;` if not set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delimiter: Option<char>,
+    /// How the lines of each shard are ordered.
+    pub sort: SortOrder,
+    /// Name that stands for a character that is not a letter or digit, at
+    /// which the key ends.
+    pub symbols: String,
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Manifest {
+            name: None,
+            depth: 3,
+            normalize: KeyNormalization::Lowercase,
+            codec: ShardCodec::Auto,
+            delimiter: None,
+            sort: SortOrder::Bytewise,
+            symbols: "symbols".to_string(),
+        }
+    }
+}
+
+impl Manifest {
+    /// Loads the manifest of the dataset at `root`, or the defaults if it
+    /// has none.
+    pub fn load(root: &Path) -> io::Result<Self> {
+        let path = root.join(MANIFEST_FILE);
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Manifest::default()),
+            Err(err) => return Err(err),
+        };
+        let manifest: Manifest = toml::from_str(&text).map_err(|err| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), err.message()))
+        })?;
+        if manifest.depth == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: depth must be at least 1", path.display()),
+            ));
+        }
+        Ok(manifest)
+    }
+
+    /// The shard key of `email`: its leading characters after
+    /// normalisation, up to the first that is not a letter or digit.
+    pub(crate) fn key(&self, email: &str) -> Vec<String> {
+        let email = email.trim();
+        let email = match self.normalize {
+            KeyNormalization::Lowercase => email.to_lowercase(),
+            KeyNormalization::None => email.to_string(),
+        };
+        let mut key = Vec::new();
+        for c in email.chars().take(self.depth) {
+            if !c.is_alphanumeric() {
+                key.push(self.symbols.clone());
+                break;
+            }
+            key.push(c.to_string());
+        }
+        key
+    }
+
+    /// Sets the manifest's delimiter, if it has one, on `parser`.
+    pub fn configure(&self, parser: RecordParser) -> RecordParser {
+        match self.delimiter {
+            Some(delimiter) => parser.delimiters(&[delimiter]),
+            None => parser,
+        }
+    }
+}
+
+/// How an address is normalised before routing it to a shard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyNormalization {
+    /// Shards are named after lowercased addresses.
+    #[default]
+    Lowercase,
+    /// Shards are named after addresses as written.
+    None,
+}
+
+/// How the shards of a dataset are compressed. Files are still decoded by
+/// their magic bytes; this only says which file to look for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ShardCodec {
+    /// Take whichever shard file exists, with or without a known
+    /// compression extension.
+    #[default]
+    Auto,
+    /// Uncompressed, with no extension.
+    None,
+    Gzip,
+    Zstd,
+    Xz,
+    Bzip2,
+    Lz4,
+}
+
+impl ShardCodec {
+    /// The extensions a shard file may have.
+    pub(crate) fn extensions(&self) -> &'static [&'static str] {
+        match self {
+            ShardCodec::Auto => &SHARD_EXTENSIONS,
+            ShardCodec::None => &[""],
+            ShardCodec::Gzip => &[".gz"],
+            ShardCodec::Zstd => &[".zst"],
+            ShardCodec::Xz => &[".xz"],
+            ShardCodec::Bzip2 => &[".bz2"],
+            ShardCodec::Lz4 => &[".lz4"],
+        }
+    }
+
+    /// The codec new shards are written in and their extension; zstd when
+    /// any will do.
+    pub(crate) fn for_new_shards(&self) -> (Codec, &'static str) {
+        match self {
+            ShardCodec::Auto | ShardCodec::Zstd => (Codec::Zstd, ".zst"),
+            ShardCodec::None => (Codec::Plain, ""),
+            ShardCodec::Gzip => (Codec::Gzip, ".gz"),
+            ShardCodec::Xz => (Codec::Xz, ".xz"),
+            ShardCodec::Bzip2 => (Codec::Bzip2, ".bz2"),
+            ShardCodec::Lz4 => (Codec::Lz4, ".lz4"),
+        }
+    }
+}
+
+/// How the lines of each shard are ordered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    /// Sorted by their bytes, so `ALICE` < `Alice` < `alice`.
+    #[default]
+    Bytewise,
+    /// Sorted by their lowercased text.
+    Lowercase,
+    /// Not sorted, so lookups read whole shards.
+    Unsorted,
+}
+
+impl SortOrder {
+    pub fn is_sorted(&self) -> bool {
+        *self != SortOrder::Unsorted
+    }
+}
@@ -0,0 +1,303 @@
+use crate::query::{self, Query, QueryError, Term};
+use aho_corasick::{AhoCorasick, AhoCorasickBuilder};
+use regex::bytes::{Regex, RegexBuilder};
+use regex_syntax::hir::literal::Extractor;
+use regex_syntax::hir::{Hir, HirKind};
+use regex_syntax::ParserBuilder;
+use std::fmt;
+
+/// How the clauses added to a [`MatcherBuilder`] combine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MatchMode {
+    /// A line must satisfy every keyword, regex and query.
+    #[default]
+    All,
+    /// A line must satisfy at least one keyword, regex or query.
+    Any,
+}
+
+/// Collects the keywords, regexes and query expressions for a [`Matcher`].
+#[derive(Debug, Clone, Default)]
+pub struct MatcherBuilder {
+    terms: Vec<Term>,
+    clauses: Vec<Query>,
+    queries: Vec<String>,
+    mode: MatchMode,
+    ignore_case: Option<bool>,
+}
+
+impl MatcherBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn keyword(self, keyword: impl Into<String>) -> Self {
+        self.term(Term::Keyword(keyword.into()))
+    }
+
+    pub fn regex(self, pattern: impl Into<String>) -> Self {
+        self.term(Term::Regex(pattern.into()))
+    }
+
+    /// Adds a boolean query expression such as
+    /// `corp.com AND NOT test OR "acme corp"`; see [`Query`] for the syntax.
+    /// The expression is parsed by [`MatcherBuilder::build`].
+    pub fn query(mut self, expr: impl Into<String>) -> Self {
+        self.queries.push(expr.into());
+        self
+    }
+
+    pub fn mode(mut self, mode: MatchMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Forces case-insensitive (`true`) or case-sensitive (`false`)
+    /// matching. By default matching ignores case only when a keyword looks
+    /// like an email address, since dumps mix `Example.COM` and
+    /// `example.com` freely.
+    pub fn ignore_case(mut self, ignore_case: bool) -> Self {
+        self.ignore_case = Some(ignore_case);
+        self
+    }
+
+    fn term(mut self, term: Term) -> Self {
+        let index = query::intern(&mut self.terms, term);
+        self.clauses.push(Query::Term(index));
+        self
+    }
+
+    pub fn build(&self) -> Result<Matcher, MatcherError> {
+        let mut terms = self.terms.clone();
+        let mut clauses = self.clauses.clone();
+        for expr in &self.queries {
+            clauses.push(Query::parse(expr, &mut terms)?);
+        }
+        let query = match self.mode {
+            MatchMode::All => Query::And(clauses),
+            MatchMode::Any => Query::Or(clauses),
+        };
+
+        let ignore_case = self.ignore_case.unwrap_or_else(|| {
+            terms
+                .iter()
+                .any(|term| matches!(term, Term::Keyword(keyword) if keyword.contains('@')))
+        });
+
+        // Aho-Corasick only folds ASCII, so non-ASCII keywords are matched
+        // as escaped regexes, which apply full Unicode case folding.
+        let mut keywords = Vec::new();
+        let mut keyword_terms = Vec::new();
+        let mut regexes = Vec::new();
+        for (index, term) in terms.iter().enumerate() {
+            match term {
+                Term::Keyword(keyword) if !ignore_case || keyword.is_ascii() => {
+                    keywords.push(keyword.as_str());
+                    keyword_terms.push(index);
+                }
+                Term::Keyword(keyword) => {
+                    regexes.push((index, RegexTerm::new(&regex::escape(keyword), ignore_case)?));
+                }
+                Term::Regex(pattern) => regexes.push((index, RegexTerm::new(pattern, ignore_case)?)),
+            }
+        }
+
+        Ok(Matcher {
+            keywords: AhoCorasickBuilder::new()
+                .ascii_case_insensitive(ignore_case)
+                .build(keywords),
+            keyword_terms,
+            regexes,
+            terms,
+            query,
+        })
+    }
+}
+
+/// Why a [`MatcherBuilder`] could not build a [`Matcher`].
+#[derive(Debug)]
+pub enum MatcherError {
+    Query(QueryError),
+    Regex(regex::Error),
+}
+
+impl fmt::Display for MatcherError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MatcherError::Query(err) => err.fmt(f),
+            MatcherError::Regex(err) => write!(f, "invalid regex: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for MatcherError {}
+
+impl From<QueryError> for MatcherError {
+    fn from(err: QueryError) -> Self {
+        MatcherError::Query(err)
+    }
+}
+
+impl From<regex::Error> for MatcherError {
+    fn from(err: regex::Error) -> Self {
+        MatcherError::Regex(err)
+    }
+}
+
+/// Decides whether a line satisfies a boolean [`Query`] over keywords and
+/// regexes.
+///
+/// All keywords are found in one overlapping Aho-Corasick pass, using its
+/// ASCII case folding when matching ignores case, so no lowercased copy of
+/// each line is ever made. Each regex is guarded by an Aho-Corasick
+/// prefilter over literals that every match must contain, so the regex
+/// engine only runs on the rare lines that could possibly match. The terms
+/// found are recorded in a bitset that the query is evaluated against.
+#[derive(Debug)]
+pub struct Matcher {
+    keywords: AhoCorasick,
+    /// Term index of each Aho-Corasick pattern.
+    keyword_terms: Vec<usize>,
+    regexes: Vec<(usize, RegexTerm)>,
+    terms: Vec<Term>,
+    query: Query,
+}
+
+#[derive(Debug)]
+struct RegexTerm {
+    regex: Regex,
+    prefilter: Option<AhoCorasick>,
+}
+
+impl RegexTerm {
+    fn new(pattern: &str, ignore_case: bool) -> Result<Self, regex::Error> {
+        let regex = RegexBuilder::new(pattern)
+            .case_insensitive(ignore_case)
+            .build()?;
+        let prefilter = required_literals(pattern, ignore_case).map(|(literals, fold_ascii)| {
+            AhoCorasickBuilder::new()
+                .ascii_case_insensitive(fold_ascii)
+                .build(literals)
+        });
+        Ok(RegexTerm { regex, prefilter })
+    }
+
+    fn is_match(&self, line: &[u8]) -> bool {
+        if let Some(prefilter) = &self.prefilter {
+            if !prefilter.is_match(line) {
+                return false;
+            }
+        }
+        self.regex.is_match(line)
+    }
+}
+
+impl Matcher {
+    /// A matcher requiring every one of `keywords`.
+    pub fn keywords<S: AsRef<str>>(keywords: &[S]) -> Self {
+        keywords
+            .iter()
+            .fold(MatcherBuilder::new(), |builder, keyword| builder.keyword(keyword.as_ref()))
+            .build()
+            .expect("escaped keywords are valid regexes")
+    }
+
+    /// Whether `line` satisfies the query. Lines are matched as bytes, so
+    /// text that is not valid UTF-8 around a match does not get in the way.
+    pub fn is_match(&self, line: impl AsRef<[u8]>) -> bool {
+        self.eval(line.as_ref(), |_| ()).is_some()
+    }
+
+    /// The terms found in `line` if it satisfies the query.
+    pub fn find(&self, line: impl AsRef<[u8]>) -> Option<Vec<&Term>> {
+        self.eval(line.as_ref(), |hits| {
+            (0..self.terms.len())
+                .filter(|&index| query::contains(hits, index))
+                .map(|index| &self.terms[index])
+                .collect()
+        })
+    }
+
+    /// Records the terms found in `line` in a bitset and, if the query
+    /// holds, returns what `found` makes of that bitset.
+    fn eval<T>(&self, line: &[u8], found: impl FnOnce(&[u64]) -> T) -> Option<T> {
+        let words = query::words_for(self.terms.len());
+        let mut inline = [0u64; 4];
+        let mut heap = Vec::new();
+        let hits = if words <= inline.len() {
+            &mut inline[..words]
+        } else {
+            heap.resize(words, 0);
+            &mut heap[..]
+        };
+
+        for found in self.keywords.find_overlapping_iter(line) {
+            query::insert(hits, self.keyword_terms[found.pattern()]);
+        }
+        for (index, term) in &self.regexes {
+            if term.is_match(line) {
+                query::insert(hits, *index);
+            }
+        }
+        self.query.eval(hits).then(|| found(hits))
+    }
+}
+
+/// A set of literals at least one of which appears in every match of
+/// `pattern`, or `None` when no selective finite set exists. The flag says
+/// whether the literals must be matched ignoring ASCII case.
+///
+/// Besides the prefixes of the whole pattern, every mandatory piece of a
+/// top-level concatenation is considered, so `^[^@]+@corp\.com:` still
+/// yields `corp.com:` even though its own prefix is unbounded.
+fn required_literals(pattern: &str, ignore_case: bool) -> Option<(Vec<Vec<u8>>, bool)> {
+    let literals = best_literals(pattern, false)?;
+    if !ignore_case {
+        return Some((literals, false));
+    }
+    if literals.iter().all(|literal| literal.is_ascii()) {
+        return Some((literals, true));
+    }
+    // Non-ASCII literals need their Unicode case variants spelled out.
+    best_literals(pattern, true).map(|literals| (literals, false))
+}
+
+fn best_literals(pattern: &str, case_insensitive: bool) -> Option<Vec<Vec<u8>>> {
+    let hir = ParserBuilder::new()
+        .case_insensitive(case_insensitive)
+        .build()
+        .parse(pattern)
+        .ok()?;
+    let best = candidate_literals(&hir)
+        .into_iter()
+        .max_by_key(|literals| {
+            let shortest = literals.iter().map(Vec::len).min().unwrap_or(0);
+            (shortest, std::cmp::Reverse(literals.len()))
+        })?;
+    let shortest = best.iter().map(Vec::len).min().unwrap_or(0);
+    // A one-byte literal such as `@` occurs on nearly every line.
+    if shortest < 2 {
+        return None;
+    }
+    Some(best)
+}
+
+fn candidate_literals(hir: &Hir) -> Vec<Vec<Vec<u8>>> {
+    let mut candidates = Vec::new();
+    let seq = Extractor::new().extract(hir);
+    if let Some(literals) = seq.literals() {
+        if !literals.is_empty() {
+            candidates.push(literals.iter().map(|lit| lit.as_bytes().to_vec()).collect());
+        }
+    }
+    match hir.kind() {
+        HirKind::Concat(children) => {
+            for child in children {
+                candidates.extend(candidate_literals(child));
+            }
+        }
+        HirKind::Capture(capture) => candidates.extend(candidate_literals(&capture.sub)),
+        _ => {}
+    }
+    candidates
+}
@@ -0,0 +1,368 @@
+use crate::block::{Lines, Position};
+use crate::codec::{open_decoded, Codec};
+use crate::dataset::Dataset;
+use crate::error::{AtPath, FileError};
+use crate::import::{normalize_record, sort_lines};
+use crate::manifest::{SortOrder, MANIFEST_FILE};
+use crate::record::RecordParser;
+use crate::shard::ShardWriter;
+use crate::sort::ExternalSorter;
+use crate::stats::Meter;
+use indicatif::ProgressBar;
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Separates the parts of a sort entry.
+const SEPARATOR: u8 = 0;
+
+/// What a merge read and wrote.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct MergeStats {
+    /// Batch files read.
+    pub files: u64,
+    pub lines: u64,
+    /// Batch records the target did not hold yet.
+    pub added: u64,
+    /// Batch records dropped as copies of another.
+    pub duplicates: u64,
+    /// Lines dropped for having no email address.
+    pub rejected: u64,
+    /// Shards rewritten or created.
+    pub shards: u64,
+}
+
+/// Merges a batch of new records, such as a directory written by an
+/// [`crate::Importer`], into an existing sorted dataset. Each batch record
+/// is normalised like an import's and routed to the shard a lookup of its
+/// email would read; every shard that gains records is rewritten in order
+/// and in its own codec, without duplicates.
+///
+/// A shard is rewritten into a temporary file that replaces it only once
+/// complete, so an interrupted merge leaves every shard either as it was
+/// or fully merged. Batch records pass through an [`ExternalSorter`], so
+/// memory use stays within the configured limit however large the batch.
+pub struct Merger {
+    target: Dataset,
+    memory_limit: usize,
+    temp_dir: PathBuf,
+    meter: Meter,
+}
+
+impl Merger {
+    /// A merger into `target`, whose shards must be sorted.
+    pub fn new(target: Dataset) -> io::Result<Self> {
+        if !target.manifest().sort.is_sorted() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{}: merges keep shards sorted, but these are unsorted", target.root().join(MANIFEST_FILE).display()),
+            ));
+        }
+        Ok(Merger {
+            target,
+            memory_limit: 512 << 20,
+            temp_dir: std::env::temp_dir(),
+            meter: Meter::new(),
+        })
+    }
+
+    /// Holds up to `bytes` of batch records (512 MiB by default) while
+    /// routing them to their shards; more are spilled to disk.
+    pub fn with_memory_limit(mut self, bytes: usize) -> Self {
+        self.memory_limit = bytes;
+        self
+    }
+
+    /// Keeps the batch records spilled while sorting under `temp_dir`
+    /// rather than in the system's temporary directory.
+    pub fn with_temp_dir(mut self, temp_dir: impl Into<PathBuf>) -> Self {
+        self.temp_dir = temp_dir.into();
+        self
+    }
+
+    /// Reports reading the batch on `progress`, in compressed bytes.
+    pub fn with_progress(mut self, progress: ProgressBar) -> Self {
+        self.meter.progress = Some(progress);
+        self
+    }
+
+    /// Merges every record of `batch` into the target. The first file that
+    /// cannot be read or written ends the merge; shards rewritten by then
+    /// stay merged.
+    pub fn merge(&self, batch: &Dataset) -> Result<MergeStats, FileError> {
+        if batch.root().canonicalize().ok() == self.target.root().canonicalize().ok() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "cannot merge a dataset into itself"))
+                .at(batch.root());
+        }
+        let files = batch.files()?;
+        let parser = batch.manifest().configure(RecordParser::default());
+        let sorter = ExternalSorter::new(&self.temp_dir, self.memory_limit).at(&self.temp_dir)?.dedup();
+        let temp = sorter.path().to_path_buf();
+        let shards = Mutex::new(HashMap::new());
+        let (pushed, rejected) = sort_lines(&files, &self.meter, &sorter, |line| self.entry(&parser, line, &shards))?;
+
+        let sorted = sorter.finish().at(&temp)?;
+        let mut stats = MergeStats {
+            files: files.len() as u64,
+            lines: self.meter.stats().lines,
+            rejected,
+            ..MergeStats::default()
+        };
+        let mut current: Option<(Vec<u8>, ShardMerge)> = None;
+        for entry in sorted {
+            let entry = entry.at(&temp)?;
+            let at = entry.iter().position(|&byte| byte == SEPARATOR).unwrap_or_default();
+            let (shard, rest) = (&entry[..at], &entry[at + 1..]);
+            let line = match self.order() {
+                // The lowercased copy of the line is as long as the line.
+                SortOrder::Lowercase => &rest[rest.len() / 2 + 1..],
+                _ => rest,
+            };
+            if current.as_ref().is_none_or(|(current, _)| current != shard) {
+                if let Some((_, merge)) = current.take() {
+                    stats.added += merge.finish()?;
+                    stats.shards += 1;
+                }
+                let path = self.target.root().join(Path::new(&*String::from_utf8_lossy(shard)));
+                current = Some((shard.to_vec(), ShardMerge::open(path, self.order(), self.new_codec())?));
+            }
+            current.as_mut().unwrap().1.push_batch(line)?;
+        }
+        if let Some((_, merge)) = current {
+            stats.added += merge.finish()?;
+            stats.shards += 1;
+        }
+        stats.duplicates = pushed - stats.added;
+        Ok(stats)
+    }
+
+    /// The sort entry for a batch line: the path of the target shard it
+    /// belongs in, then, for shards sorted by lowercased lines, the line
+    /// lowercased, then the normalised line. `None` if it has no email.
+    fn entry(
+        &self,
+        parser: &RecordParser,
+        line: Vec<u8>,
+        shards: &Mutex<HashMap<String, String>>,
+    ) -> Option<Vec<u8>> {
+        let manifest = self.target.manifest();
+        let (email, line) = normalize_record(parser, line, manifest.delimiter.unwrap_or(':'))?;
+        let key = manifest.key(&email).join("/");
+        let mut shards = shards.lock().unwrap();
+        let shard = shards.entry(key).or_insert_with(|| {
+            let path = self
+                .target
+                .shard_path(&email)
+                .unwrap_or_else(|_| self.target.new_shard_path(&email));
+            self.target.relative_path(&path)
+        });
+
+        let mut entry = Vec::with_capacity(shard.len() + 2 * line.len() + 2);
+        entry.extend_from_slice(shard.as_bytes());
+        entry.push(SEPARATOR);
+        if self.order() == SortOrder::Lowercase {
+            entry.extend(line.iter().map(u8::to_ascii_lowercase));
+            entry.push(SEPARATOR);
+        }
+        entry.extend_from_slice(&line);
+        Some(entry)
+    }
+
+    fn order(&self) -> SortOrder {
+        self.target.manifest().sort
+    }
+
+    fn new_codec(&self) -> Codec {
+        self.target.manifest().codec.for_new_shards().0
+    }
+}
+
+/// One shard being rewritten: its existing lines, read as the batch lines
+/// meant for it arrive, interleaved with them in order.
+struct ShardMerge {
+    path: PathBuf,
+    order: SortOrder,
+    existing: Option<Lines<Box<dyn BufRead>>>,
+    next: Option<Vec<u8>>,
+    writer: ShardWriter,
+    last: Option<Vec<u8>>,
+    added: u64,
+}
+
+impl ShardMerge {
+    /// Starts rewriting the shard at `path`, or writing it in `new_codec`
+    /// if there is none yet.
+    fn open(path: PathBuf, order: SortOrder, new_codec: Codec) -> Result<Self, FileError> {
+        let (existing, codec) = if path.is_file() {
+            let codec = Codec::of(&path).at(&path)?;
+            (Some(Lines::new(open_decoded(&path).at(&path)?, Position::default())), codec)
+        } else {
+            (None, new_codec)
+        };
+        let writer = ShardWriter::create(&path, codec).at(&path)?;
+        let mut merge = ShardMerge {
+            path,
+            order,
+            existing,
+            next: None,
+            writer,
+            last: None,
+            added: 0,
+        };
+        merge.next = merge.read_existing()?;
+        Ok(merge)
+    }
+
+    fn read_existing(&mut self) -> Result<Option<Vec<u8>>, FileError> {
+        match self.existing.as_mut().and_then(Iterator::next) {
+            Some(line) => Ok(Some(line.at(&self.path)?.1)),
+            None => Ok(None),
+        }
+    }
+
+    /// Writes the existing lines that sort before the batch line `line`,
+    /// then `line` itself unless the shard already holds it.
+    fn push_batch(&mut self, line: &[u8]) -> Result<(), FileError> {
+        while let Some(existing) = self.next.take() {
+            if compare(self.order, &existing, line) == Ordering::Greater {
+                self.next = Some(existing);
+                break;
+            }
+            self.write(existing)?;
+            self.next = self.read_existing()?;
+        }
+        if self.write(line.to_vec())? {
+            self.added += 1;
+        }
+        Ok(())
+    }
+
+    /// Writes `line` unless it repeats the line before, and returns whether
+    /// it did.
+    fn write(&mut self, line: Vec<u8>) -> Result<bool, FileError> {
+        if self.last.as_ref() == Some(&line) {
+            return Ok(false);
+        }
+        self.writer.write_line(&line).at(&self.path)?;
+        self.last = Some(line);
+        Ok(true)
+    }
+
+    /// Writes the remaining existing lines and puts the merged shard in
+    /// place. Returns how many batch lines it gained.
+    fn finish(mut self) -> Result<u64, FileError> {
+        while let Some(existing) = self.next.take() {
+            self.write(existing)?;
+            self.next = self.read_existing()?;
+        }
+        self.writer.finish().at(&self.path)?;
+        Ok(self.added)
+    }
+}
+
+/// How two shard lines are ordered in a shard sorted by `order`; lines
+/// equal but for case are ordered bytewise.
+fn compare(order: SortOrder, a: &[u8], b: &[u8]) -> Ordering {
+    match order {
+        SortOrder::Lowercase => a
+            .iter()
+            .map(u8::to_ascii_lowercase)
+            .cmp(b.iter().map(u8::to_ascii_lowercase))
+            .then_with(|| a.cmp(b)),
+        _ => a.cmp(b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sort::Scratch;
+    use std::fs;
+
+    /// Writes `files`, as relative path and contents, under `root`.
+    fn write_files(root: &Path, files: &[(&str, &str)]) {
+        for (path, contents) in files {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+    }
+
+    /// The lines of the shard of `target` holding `email`.
+    fn shard_lines(target: &Path, email: &str) -> Vec<String> {
+        let path = Dataset::open(target).unwrap().shard_path(email).unwrap();
+        open_decoded(&path).unwrap().lines().map(Result::unwrap).collect()
+    }
+
+    fn merge(scratch: &Scratch) -> MergeStats {
+        let target = Dataset::open(scratch.path().join("target")).unwrap();
+        let batch = Dataset::open(scratch.path().join("batch")).unwrap();
+        Merger::new(target)
+            .unwrap()
+            .with_temp_dir(scratch.path())
+            .merge(&batch)
+            .unwrap()
+    }
+
+    #[test]
+    fn merges_in_order_without_duplicates() {
+        let scratch = Scratch::new();
+        write_files(
+            scratch.path(),
+            &[
+                ("target/b/o/b", "bob@x.com:a\nbob@x.com:c\n"),
+                ("batch/one.txt", "bob@x.com:b\nBOB@x.com:c\nbob@x.com:a\n"),
+                ("batch/two.txt", "bob@x.com:b\nbobby@x.com:z\namy@x.com:1\nno email\n"),
+            ],
+        );
+
+        let stats = merge(&scratch);
+        let target = scratch.path().join("target");
+        assert_eq!(
+            shard_lines(&target, "bob@x.com"),
+            ["bob@x.com:a", "bob@x.com:b", "bob@x.com:c", "bobby@x.com:z"]
+        );
+        assert_eq!(shard_lines(&target, "amy@x.com"), ["amy@x.com:1"]);
+        assert_eq!(
+            stats,
+            MergeStats {
+                files: 2,
+                lines: 7,
+                added: 3,
+                duplicates: 3,
+                rejected: 1,
+                shards: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn keeps_lowercase_order() {
+        let scratch = Scratch::new();
+        write_files(
+            scratch.path(),
+            &[
+                ("target/dataset.toml", "sort = \"lowercase\"\n"),
+                ("target/b/o/b", "bob@x.com:B\nbob@x.com:c\n"),
+                ("batch/one.txt", "bob@x.com:b\nbob@x.com:a\nbob@x.com:B\n"),
+            ],
+        );
+
+        let stats = merge(&scratch);
+        assert_eq!(
+            shard_lines(&scratch.path().join("target"), "bob@x.com"),
+            ["bob@x.com:a", "bob@x.com:B", "bob@x.com:b", "bob@x.com:c"]
+        );
+        assert_eq!((stats.added, stats.duplicates), (2, 1));
+    }
+
+    #[test]
+    fn refuses_unsorted_targets() {
+        let scratch = Scratch::new();
+        write_files(scratch.path(), &[("target/dataset.toml", "sort = \"unsorted\"\n")]);
+        assert!(Merger::new(Dataset::open(scratch.path().join("target")).unwrap()).is_err());
+    }
+}
@@ -0,0 +1,561 @@
+use crate::dedupe::GroupedAccount;
+use crate::domain::Account;
+use crate::record::InvalidUtf8;
+use crate::redact::Redaction;
+use crate::search::Hit;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+/// How hits are written out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    /// The matching lines as they appear in the dumps.
+    #[default]
+    Text,
+    /// One JSON object per hit (or per account for account reports).
+    JsonLines,
+    /// Comma-separated values, quoted as in RFC 4180.
+    Csv,
+    /// Tab-separated values, with tabs, newlines and backslashes escaped.
+    Tsv,
+}
+
+/// A field of a hit that can be selected for CSV and TSV output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Column {
+    Dataset,
+    Email,
+    Username,
+    Password,
+    Url,
+    Domain,
+    Extra,
+    Source,
+    LineNumber,
+    Offset,
+    Patterns,
+    /// The whole line as it appears in the dump.
+    Line,
+}
+
+impl Column {
+    /// The columns written when none are selected.
+    pub const DEFAULT: &'static [Column] = &[
+        Column::Email,
+        Column::Password,
+        Column::Source,
+        Column::LineNumber,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Column::Dataset => "dataset",
+            Column::Email => "email",
+            Column::Username => "username",
+            Column::Password => "password",
+            Column::Url => "url",
+            Column::Domain => "domain",
+            Column::Extra => "extra",
+            Column::Source => "source",
+            Column::LineNumber => "line_number",
+            Column::Offset => "offset",
+            Column::Patterns => "patterns",
+            Column::Line => "line",
+        }
+    }
+
+    fn value<'a>(self, hit: &'a Hit, redaction: &Redaction, invalid_utf8: InvalidUtf8) -> Cow<'a, [u8]> {
+        let record = &hit.record;
+        let text: Cow<'a, str> = match self {
+            Column::Dataset => hit.dataset.as_str().into(),
+            Column::Email => record.email().unwrap_or_default().into(),
+            Column::Username => record.username().unwrap_or_default().into(),
+            Column::Password => redaction.record_password(record).unwrap_or_default(),
+            Column::Url => record.url().unwrap_or_default().into(),
+            Column::Domain => record.domain().unwrap_or_default().into(),
+            Column::Extra => record.extra().collect::<Vec<_>>().join("|").into(),
+            Column::Source => hit.source.as_str().into(),
+            Column::LineNumber => hit.line_number.to_string().into(),
+            Column::Offset => hit.offset.to_string().into(),
+            Column::Patterns => hit.patterns.join("|").into(),
+            Column::Line if invalid_utf8 == InvalidUtf8::Raw => return redaction.raw_line(record),
+            Column::Line => redaction.line(record),
+        };
+        match text {
+            Cow::Borrowed(text) => Cow::Borrowed(text.as_bytes()),
+            Cow::Owned(text) => Cow::Owned(text.into_bytes()),
+        }
+    }
+}
+
+impl FromStr for Column {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Ok(match name.trim() {
+            "dataset" => Column::Dataset,
+            "email" => Column::Email,
+            "username" | "user" => Column::Username,
+            "password" => Column::Password,
+            "url" => Column::Url,
+            "domain" => Column::Domain,
+            "extra" => Column::Extra,
+            "source" => Column::Source,
+            "line_number" | "line_no" => Column::LineNumber,
+            "offset" => Column::Offset,
+            "patterns" => Column::Patterns,
+            "line" => Column::Line,
+            other => return Err(format!("unknown column '{}'", other)),
+        })
+    }
+}
+
+/// The serializable form of a [`Hit`], with its record split into fields.
+///
+/// Built with `From<&Hit>`, the password is unredacted; [`HitWriter`]
+/// applies its [`Redaction`] before writing rows.
+#[derive(Debug, Clone, Serialize)]
+pub struct HitRow<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<Cow<'a, str>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<&'a str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub extra: Vec<&'a str>,
+    pub dataset: &'a str,
+    pub source: &'a str,
+    pub line_number: u64,
+    pub offset: u64,
+    pub patterns: &'a [String],
+}
+
+impl<'a> From<&'a Hit> for HitRow<'a> {
+    fn from(hit: &'a Hit) -> Self {
+        HitRow {
+            email: hit.record.email(),
+            username: hit.record.username(),
+            password: hit.record.password().map(Cow::Borrowed),
+            url: hit.record.url(),
+            extra: hit.record.extra().collect(),
+            dataset: &hit.dataset,
+            source: &hit.source,
+            line_number: hit.line_number,
+            offset: hit.offset,
+            patterns: &hit.patterns,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct AccountRow<'a> {
+    email: &'a str,
+    hits: Vec<HitRow<'a>>,
+}
+
+#[derive(Serialize)]
+struct GroupRow<'a> {
+    email: &'a str,
+    passwords: Vec<PasswordRow<'a>>,
+}
+
+#[derive(Serialize)]
+struct PasswordRow<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password: Option<Cow<'a, str>>,
+    sources: Vec<SourceRow<'a>>,
+}
+
+#[derive(Serialize)]
+struct SourceRow<'a> {
+    dataset: &'a str,
+    source: &'a str,
+    line_number: u64,
+}
+
+/// The CSV and TSV columns of grouped accounts, one row per password.
+const GROUP_COLUMNS: &[&str] = &["email", "password", "sources"];
+
+/// Writes hits and account reports in a [`Format`].
+pub struct HitWriter<W: Write> {
+    out: W,
+    format: Format,
+    columns: Vec<Column>,
+    header: bool,
+    redaction: Redaction,
+    invalid_utf8: InvalidUtf8,
+    dataset_names: bool,
+    groups: bool,
+    accounts: usize,
+    entries: usize,
+}
+
+impl<W: Write> HitWriter<W> {
+    pub fn new(out: W, format: Format) -> Self {
+        HitWriter {
+            out,
+            format,
+            columns: Column::DEFAULT.to_vec(),
+            header: true,
+            redaction: Redaction::None,
+            invalid_utf8: InvalidUtf8::Lossy,
+            dataset_names: false,
+            groups: false,
+            accounts: 0,
+            entries: 0,
+        }
+    }
+
+    /// Selects and orders the CSV and TSV columns.
+    pub fn with_columns(mut self, columns: Vec<Column>) -> Self {
+        self.columns = columns;
+        self
+    }
+
+    /// Whether CSV and TSV output starts with a header row (the default).
+    pub fn with_header(mut self, header: bool) -> Self {
+        self.header = header;
+        self
+    }
+
+    /// How passwords are shown, in every format (unredacted by default).
+    pub fn with_redaction(mut self, redaction: Redaction) -> Self {
+        self.redaction = redaction;
+        self
+    }
+
+    /// With [`InvalidUtf8::Raw`], text output and the `line` column of CSV
+    /// and TSV output are written byte for byte as in the dump. Anything
+    /// else is written as decoded by the [`crate::RecordParser`].
+    pub fn with_invalid_utf8(mut self, invalid_utf8: InvalidUtf8) -> Self {
+        self.invalid_utf8 = invalid_utf8;
+        self
+    }
+
+    /// Whether text output prefixes each line with the name of its dataset,
+    /// as in `[collection-1] alice@example.com:hunter2`, for searches
+    /// across several datasets.
+    pub fn with_dataset_names(mut self, dataset_names: bool) -> Self {
+        self.dataset_names = dataset_names;
+        self
+    }
+
+    /// Whether the output is of grouped accounts, so that empty CSV and TSV
+    /// output gets the header row of [`HitWriter::write_group`] rather than
+    /// that of the columns.
+    pub fn with_groups(mut self, groups: bool) -> Self {
+        self.groups = groups;
+        self
+    }
+
+    pub fn write_hit(&mut self, hit: &Hit) -> io::Result<()> {
+        match self.format {
+            Format::Text => self.write_line(hit, ""),
+            Format::JsonLines => {
+                serde_json::to_writer(&mut self.out, &redacted_row(hit, &self.redaction))?;
+                writeln!(self.out)
+            }
+            Format::Csv | Format::Tsv => {
+                let values: Vec<_> = self
+                    .columns
+                    .iter()
+                    .map(|column| column.value(hit, &self.redaction, self.invalid_utf8))
+                    .collect();
+                self.write_row(&values)
+            }
+        }
+    }
+
+    /// Writes the hit's line after `indent`.
+    fn write_line(&mut self, hit: &Hit, indent: &str) -> io::Result<()> {
+        self.out.write_all(indent.as_bytes())?;
+        if self.dataset_names {
+            write!(self.out, "[{}] ", hit.dataset)?;
+        }
+        if self.invalid_utf8 == InvalidUtf8::Raw {
+            self.out.write_all(&self.redaction.raw_line(&hit.record))?;
+        } else {
+            self.out.write_all(self.redaction.line(&hit.record).as_bytes())?;
+        }
+        writeln!(self.out)
+    }
+
+    /// Writes one delimited row, preceded by the header row the first time.
+    fn write_row<S: AsRef<[u8]>>(&mut self, values: &[S]) -> io::Result<()> {
+        let names: Vec<_> = self.columns.iter().map(|column| column.name()).collect();
+        self.write_row_under(&names, values)
+    }
+
+    /// Writes one delimited row, preceded by a header row of `names` the
+    /// first time.
+    fn write_row_under<S: AsRef<[u8]>>(&mut self, names: &[&str], values: &[S]) -> io::Result<()> {
+        if self.header {
+            self.header = false;
+            self.write_row_under(names, names)?;
+        }
+        for (i, value) in values.iter().enumerate() {
+            if i > 0 {
+                self.out.write_all(if self.format == Format::Csv { b"," } else { b"\t" })?;
+            }
+            match self.format {
+                Format::Csv => write_csv_field(&mut self.out, value.as_ref())?,
+                _ => write_tsv_field(&mut self.out, value.as_ref())?,
+            }
+        }
+        writeln!(self.out)
+    }
+
+    /// Writes one account with every hit found for it, including accounts
+    /// with none.
+    pub fn write_account(&mut self, account: &Account) -> io::Result<()> {
+        self.accounts += 1;
+        self.entries += account.hits.len();
+        match self.format {
+            Format::Text => {
+                writeln!(self.out, "{} ({})", account.email, account.hits.len())?;
+                for hit in &account.hits {
+                    self.write_line(hit, "    ")?;
+                }
+                Ok(())
+            }
+            Format::JsonLines => {
+                let row = AccountRow {
+                    email: &account.email,
+                    hits: account.hits.iter().map(|hit| redacted_row(hit, &self.redaction)).collect(),
+                };
+                serde_json::to_writer(&mut self.out, &row)?;
+                writeln!(self.out)
+            }
+            Format::Csv | Format::Tsv if account.hits.is_empty() => {
+                // A miss still gets a row, with only the email filled in.
+                let values: Vec<&str> = self
+                    .columns
+                    .iter()
+                    .map(|&column| if column == Column::Email { account.email.as_str() } else { "" })
+                    .collect();
+                self.write_row(&values)
+            }
+            Format::Csv | Format::Tsv => account.hits.iter().try_for_each(|hit| self.write_hit(hit)),
+        }
+    }
+
+    /// Writes one account with each distinct password found for it and
+    /// where each was seen, as `source:line`. CSV and TSV output has one
+    /// row per password, with the sources separated by `|`.
+    pub fn write_group(&mut self, account: &GroupedAccount) -> io::Result<()> {
+        self.accounts += 1;
+        self.entries += account.passwords.len();
+        match self.format {
+            Format::Text => {
+                let plural = if account.passwords.len() == 1 { "" } else { "s" };
+                writeln!(self.out, "{} ({} password{})", account.email, account.passwords.len(), plural)?;
+                for password in &account.passwords {
+                    let sources: Vec<_> = password.hits.iter().map(|hit| self.source(hit)).collect();
+                    match self.redaction.password(&password.password) {
+                        Some(shown) => writeln!(self.out, "    {} ({})", shown, sources.join(", "))?,
+                        None => writeln!(self.out, "    ({})", sources.join(", "))?,
+                    }
+                }
+                Ok(())
+            }
+            Format::JsonLines => {
+                let row = GroupRow {
+                    email: &account.email,
+                    passwords: account
+                        .passwords
+                        .iter()
+                        .map(|password| PasswordRow {
+                            password: self.redaction.password(&password.password),
+                            sources: password
+                                .hits
+                                .iter()
+                                .map(|hit| SourceRow {
+                                    dataset: &hit.dataset,
+                                    source: &hit.source,
+                                    line_number: hit.line_number,
+                                })
+                                .collect(),
+                        })
+                        .collect(),
+                };
+                serde_json::to_writer(&mut self.out, &row)?;
+                writeln!(self.out)
+            }
+            Format::Csv | Format::Tsv => {
+                for password in &account.passwords {
+                    let sources: Vec<_> = password.hits.iter().map(|hit| self.source(hit)).collect();
+                    let shown = self.redaction.password(&password.password).unwrap_or_default();
+                    self.write_row_under(GROUP_COLUMNS, &[account.email.as_str(), &shown, &sources.join("|")])?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Where a hit was seen, as `source:line`, after its dataset's name
+    /// when searching several.
+    fn source(&self, hit: &Hit) -> String {
+        if self.dataset_names {
+            format!("[{}] {}:{}", hit.dataset, hit.source, hit.line_number)
+        } else {
+            format!("{}:{}", hit.source, hit.line_number)
+        }
+    }
+
+    /// How many accounts have been written, by [`HitWriter::write_account`]
+    /// or [`HitWriter::write_group`].
+    pub fn accounts(&self) -> usize {
+        self.accounts
+    }
+
+    /// How many entries the accounts written held: hits, or distinct
+    /// passwords of grouped accounts.
+    pub fn entries(&self) -> usize {
+        self.entries
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    /// Ends the output, adding the header row to empty CSV and TSV output.
+    pub fn finish(mut self) -> io::Result<()> {
+        if matches!(self.format, Format::Csv | Format::Tsv) && self.header {
+            self.header = false;
+            let names: Vec<_> = match self.groups {
+                true => GROUP_COLUMNS.to_vec(),
+                false => self.columns.iter().map(|column| column.name()).collect(),
+            };
+            self.write_row_under(&names, &names)?;
+        }
+        self.out.flush()
+    }
+}
+
+fn redacted_row<'a>(hit: &'a Hit, redaction: &Redaction) -> HitRow<'a> {
+    HitRow {
+        password: redaction.record_password(&hit.record),
+        ..HitRow::from(hit)
+    }
+}
+
+fn write_csv_field(out: &mut impl Write, value: &[u8]) -> io::Result<()> {
+    if !value.iter().any(|byte| matches!(byte, b',' | b'"' | b'\r' | b'\n')) {
+        return out.write_all(value);
+    }
+    out.write_all(b"\"")?;
+    for field in value.split_inclusive(|&byte| byte == b'"') {
+        out.write_all(field)?;
+        if field.ends_with(b"\"") {
+            out.write_all(b"\"")?;
+        }
+    }
+    out.write_all(b"\"")
+}
+
+fn write_tsv_field(out: &mut impl Write, value: &[u8]) -> io::Result<()> {
+    for &byte in value {
+        match byte {
+            b'\\' => out.write_all(b"\\\\")?,
+            b'\t' => out.write_all(b"\\t")?,
+            b'\n' => out.write_all(b"\\n")?,
+            b'\r' => out.write_all(b"\\r")?,
+            byte => out.write_all(&[byte])?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::RecordParser;
+
+    fn hit(line: &str) -> Hit {
+        Hit {
+            record: RecordParser::new().parse(line),
+            dataset: "leak".to_string(),
+            source: "a/one.txt".to_string(),
+            line_number: 3,
+            offset: 40,
+            patterns: vec!["x.com".to_string()],
+        }
+    }
+
+    fn write(format: Format, columns: Vec<Column>, lines: &[&str]) -> String {
+        let mut out = Vec::new();
+        let mut writer = HitWriter::new(&mut out, format).with_columns(columns);
+        for line in lines {
+            writer.write_hit(&hit(line)).unwrap();
+        }
+        writer.finish().unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn csv_field(value: &[u8]) -> String {
+        let mut out = Vec::new();
+        write_csv_field(&mut out, value).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn csv_quotes_fields_with_commas_and_quotes() {
+        let out = write(
+            Format::Csv,
+            Column::DEFAULT.to_vec(),
+            &["bob@x.com:pa,ss", r#"amy@x.com:say "hi""#, "cat@x.com:plain"],
+        );
+        assert_eq!(
+            out,
+            concat!(
+                "email,password,source,line_number\n",
+                "bob@x.com,\"pa,ss\",a/one.txt,3\n",
+                "amy@x.com,\"say \"\"hi\"\"\",a/one.txt,3\n",
+                "cat@x.com,plain,a/one.txt,3\n",
+            )
+        );
+    }
+
+    #[test]
+    fn csv_quotes_fields_with_newlines() {
+        assert_eq!(csv_field(b"a\nb"), "\"a\nb\"");
+        assert_eq!(csv_field(b"a\r\nb"), "\"a\r\nb\"");
+        assert_eq!(csv_field(b"\""), "\"\"\"\"");
+        assert_eq!(csv_field(b""), "");
+    }
+
+    #[test]
+    fn csv_header_is_written_without_hits() {
+        let out = write(Format::Csv, vec![Column::Email, Column::Line], &[]);
+        assert_eq!(out, "email,line\n");
+    }
+
+    #[test]
+    fn grouped_csv_header_is_written_without_accounts() {
+        let mut out = Vec::new();
+        HitWriter::new(&mut out, Format::Csv).with_groups(true).finish().unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "email,password,sources\n");
+    }
+
+    #[test]
+    fn hashes_are_of_the_raw_password_bytes() {
+        let hash = Redaction::Hash { key: None };
+        let digest = |line: &[u8]| {
+            let hit = Hit { record: RecordParser::new().parse_bytes(line.to_vec()), ..hit("") };
+            Column::Password.value(&hit, &hash, InvalidUtf8::Lossy).into_owned()
+        };
+        assert_ne!(digest(b"bob@x.com:pw\xff"), digest(b"bob@x.com:pw\xfe"));
+        assert_eq!(digest(b"bob@x.com:pw"), digest(b"amy@x.com:pw"));
+    }
+
+    #[test]
+    fn tsv_escapes_tabs_and_backslashes() {
+        let out = write(Format::Tsv, vec![Column::Email, Column::Password], &["bob@x.com:a\tb\\c"]);
+        assert_eq!(out, "email\tpassword\nbob@x.com\ta\\tb\\\\c\n");
+    }
+}
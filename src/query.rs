@@ -0,0 +1,343 @@
+use std::fmt;
+
+/// A leaf of a query: something that either occurs in a line or does not.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Term {
+    /// A literal substring, written as a bare word or a `"quoted phrase"`.
+    Keyword(String),
+    /// A regular expression, written between slashes: `/^admin@/`.
+    Regex(String),
+}
+
+impl fmt::Display for Term {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Term::Keyword(keyword) => f.write_str(keyword),
+            Term::Regex(regex) => write!(f, "/{}/", regex),
+        }
+    }
+}
+
+/// A boolean expression over [`Term`]s, referenced by index.
+///
+/// `NOT` binds tightest, then `AND`, then `OR`, so
+/// `corp.com AND NOT test OR "acme corp"` reads as
+/// `(corp.com AND (NOT test)) OR "acme corp"`. Adjacent terms without an
+/// operator are ANDed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    Term(usize),
+    Not(Box<Query>),
+    And(Vec<Query>),
+    Or(Vec<Query>),
+}
+
+impl Query {
+    /// Evaluates the query given the set of terms found in a line.
+    pub fn eval(&self, hits: &[u64]) -> bool {
+        match self {
+            Query::Term(index) => contains(hits, *index),
+            Query::Not(query) => !query.eval(hits),
+            Query::And(queries) => queries.iter().all(|query| query.eval(hits)),
+            Query::Or(queries) => queries.iter().any(|query| query.eval(hits)),
+        }
+    }
+
+    /// Parses `expr`, interning its terms into `terms` so that repeated
+    /// terms share one index.
+    pub fn parse(expr: &str, terms: &mut Vec<Term>) -> Result<Query, QueryError> {
+        let tokens = tokenize(expr)?;
+        let mut parser = Parser { tokens, pos: 0, terms };
+        let query = parser.or()?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok(query),
+            Some(token) => Err(QueryError(format!("unexpected {}", token))),
+        }
+    }
+}
+
+/// Number of `u64` words needed to hold one bit per term.
+pub(crate) fn words_for(term_count: usize) -> usize {
+    term_count.div_ceil(64)
+}
+
+pub(crate) fn insert(hits: &mut [u64], index: usize) {
+    hits[index / 64] |= 1 << (index % 64);
+}
+
+pub(crate) fn contains(hits: &[u64], index: usize) -> bool {
+    hits[index / 64] & (1 << (index % 64)) != 0
+}
+
+/// Interns `term`, returning its index in `terms`.
+pub(crate) fn intern(terms: &mut Vec<Term>, term: Term) -> usize {
+    match terms.iter().position(|existing| *existing == term) {
+        Some(index) => index,
+        None => {
+            terms.push(term);
+            terms.len() - 1
+        }
+    }
+}
+
+/// A malformed query expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryError(String);
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid query: {}", self.0)
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+    Term(Term),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::And => f.write_str("AND"),
+            Token::Or => f.write_str("OR"),
+            Token::Not => f.write_str("NOT"),
+            Token::Open => f.write_str("'('"),
+            Token::Close => f.write_str("')'"),
+            Token::Term(Term::Keyword(keyword)) => write!(f, "\"{}\"", keyword),
+            Token::Term(Term::Regex(regex)) => write!(f, "/{}/", regex),
+        }
+    }
+}
+
+fn tokenize(expr: &str) -> Result<Vec<Token>, QueryError> {
+    let mut tokens = Vec::new();
+    let mut chars = expr.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::Open);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::Close);
+            }
+            '"' | '/' => {
+                chars.next();
+                let unterminated = || QueryError(format!("unterminated {}", c));
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\\') if c == '"' => text.push(chars.next().ok_or_else(unterminated)?),
+                        // Keep the backslash so the regex sees `\/` as `/`
+                        // and other escapes unchanged.
+                        Some('\\') => match chars.next().ok_or_else(unterminated)? {
+                            '/' => text.push('/'),
+                            escaped => {
+                                text.push('\\');
+                                text.push(escaped);
+                            }
+                        },
+                        Some(end) if end == c => {
+                            tokens.push(Token::Term(if c == '"' {
+                                Term::Keyword(text)
+                            } else {
+                                Term::Regex(text)
+                            }));
+                            break;
+                        }
+                        Some(other) => text.push(other),
+                        None => return Err(unterminated()),
+                    }
+                }
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == '(' || c == ')' {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push(match word.as_str() {
+                    "AND" => Token::And,
+                    "OR" => Token::Or,
+                    "NOT" => Token::Not,
+                    _ => Token::Term(Term::Keyword(word)),
+                });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    terms: &'a mut Vec<Term>,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn or(&mut self) -> Result<Query, QueryError> {
+        let mut queries = vec![self.and()?];
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            queries.push(self.and()?);
+        }
+        Ok(if queries.len() == 1 { queries.pop().unwrap() } else { Query::Or(queries) })
+    }
+
+    fn and(&mut self) -> Result<Query, QueryError> {
+        let mut queries = vec![self.unary()?];
+        loop {
+            match self.peek() {
+                Some(Token::And) => self.pos += 1,
+                Some(Token::Not | Token::Open | Token::Term(_)) => {}
+                _ => break,
+            }
+            queries.push(self.unary()?);
+        }
+        Ok(if queries.len() == 1 { queries.pop().unwrap() } else { Query::And(queries) })
+    }
+
+    fn unary(&mut self) -> Result<Query, QueryError> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Not) => Ok(Query::Not(Box::new(self.unary()?))),
+            Some(Token::Open) => {
+                let query = self.or()?;
+                match self.peek() {
+                    Some(Token::Close) => {
+                        self.pos += 1;
+                        Ok(query)
+                    }
+                    _ => Err(QueryError("missing ')'".to_string())),
+                }
+            }
+            Some(Token::Term(term)) => {
+                match &term {
+                    Term::Keyword(keyword) if keyword.is_empty() => {
+                        return Err(QueryError("empty phrase".to_string()));
+                    }
+                    Term::Regex(regex) if regex.is_empty() => {
+                        return Err(QueryError("empty regex".to_string()));
+                    }
+                    _ => {}
+                }
+                Ok(Query::Term(intern(self.terms, term)))
+            }
+            Some(token) => Err(QueryError(format!("unexpected {}", token))),
+            None => Err(QueryError("unexpected end of query".to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(expr: &str) -> (Query, Vec<Term>) {
+        let mut terms = Vec::new();
+        let query = Query::parse(expr, &mut terms).unwrap();
+        (query, terms)
+    }
+
+    fn keyword(text: &str) -> Term {
+        Term::Keyword(text.to_string())
+    }
+
+    #[test]
+    fn not_binds_tighter_than_and_and_and_than_or() {
+        let (query, terms) = parse("a AND NOT b OR c");
+        assert_eq!(terms, [keyword("a"), keyword("b"), keyword("c")]);
+        assert_eq!(
+            query,
+            Query::Or(vec![
+                Query::And(vec![Query::Term(0), Query::Not(Box::new(Query::Term(1)))]),
+                Query::Term(2),
+            ])
+        );
+    }
+
+    #[test]
+    fn adjacent_terms_are_anded() {
+        let (query, _) = parse("a b OR c");
+        assert_eq!(query, Query::Or(vec![Query::And(vec![Query::Term(0), Query::Term(1)]), Query::Term(2)]));
+    }
+
+    #[test]
+    fn parentheses_group() {
+        let (query, _) = parse("a AND (b OR c)");
+        assert_eq!(query, Query::And(vec![Query::Term(0), Query::Or(vec![Query::Term(1), Query::Term(2)])]));
+    }
+
+    #[test]
+    fn repeated_terms_share_an_index() {
+        let (query, terms) = parse("a OR NOT a");
+        assert_eq!(terms, [keyword("a")]);
+        assert_eq!(query, Query::Or(vec![Query::Term(0), Query::Not(Box::new(Query::Term(0)))]));
+    }
+
+    #[test]
+    fn unbalanced_parentheses_are_rejected() {
+        let mut terms = Vec::new();
+        assert_eq!(Query::parse("(a OR b", &mut terms), Err(QueryError("missing ')'".to_string())));
+        assert_eq!(Query::parse("a OR b)", &mut terms), Err(QueryError("unexpected ')'".to_string())));
+        assert!(Query::parse("()", &mut terms).is_err());
+    }
+
+    #[test]
+    fn quoted_keywords_are_terms() {
+        let (query, terms) = parse(r#""AND" OR "acme corp""#);
+        assert_eq!(terms, [keyword("AND"), keyword("acme corp")]);
+        assert_eq!(query, Query::Or(vec![Query::Term(0), Query::Term(1)]));
+    }
+
+    #[test]
+    fn escapes_in_phrases_and_regexes() {
+        let (_, terms) = parse(r#""say \"hi\" \\ bye" /a\/b\d/"#);
+        assert_eq!(terms, [keyword(r#"say "hi" \ bye"#), Term::Regex(r"a/b\d".to_string())]);
+    }
+
+    #[test]
+    fn unterminated_and_empty_phrases_and_regexes_are_rejected() {
+        let mut terms = Vec::new();
+        assert_eq!(Query::parse(r#""abc"#, &mut terms), Err(QueryError("unterminated \"".to_string())));
+        assert_eq!(Query::parse("/abc", &mut terms), Err(QueryError("unterminated /".to_string())));
+        assert_eq!(Query::parse(r#"bob "x.com\"#, &mut terms), Err(QueryError("unterminated \"".to_string())));
+        assert_eq!(Query::parse(r"bob /x\", &mut terms), Err(QueryError("unterminated /".to_string())));
+        assert_eq!(Query::parse(r#""""#, &mut terms), Err(QueryError("empty phrase".to_string())));
+        assert_eq!(Query::parse("//", &mut terms), Err(QueryError("empty regex".to_string())));
+        assert_eq!(Query::parse("a AND", &mut terms), Err(QueryError("unexpected end of query".to_string())));
+    }
+
+    #[test]
+    fn eval_follows_precedence() {
+        let (query, _) = parse("a AND NOT b OR c");
+        let hits = |found: &[usize]| {
+            let mut hits = vec![0; words_for(3)];
+            found.iter().for_each(|&index| insert(&mut hits, index));
+            hits
+        };
+        assert!(query.eval(&hits(&[0])));
+        assert!(!query.eval(&hits(&[0, 1])));
+        assert!(query.eval(&hits(&[0, 1, 2])));
+        assert!(!query.eval(&hits(&[])));
+    }
+}
@@ -0,0 +1,446 @@
+use std::fmt::Write;
+use std::ops::Range;
+
+/// How the fields of a dump line are laid out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Layout {
+    /// Work out the layout of each line on its own; see [`RecordParser`].
+    #[default]
+    Auto,
+    /// `email:password`
+    EmailPass,
+    /// `user:password`
+    UserPass,
+    /// `url:email:password` or `url:user:password`
+    UrlEmailPass,
+    /// Tab-separated `email<TAB>password<TAB>extra...`
+    Tsv,
+}
+
+/// What to do with bytes that are not valid UTF-8, which dumps in Latin-1
+/// or mangled encodings are full of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InvalidUtf8 {
+    /// Replace each invalid sequence with `U+FFFD`.
+    #[default]
+    Lossy,
+    /// Write each invalid byte as a `\xNN` escape.
+    Hex,
+    /// Decode like [`InvalidUtf8::Lossy`] for parsing, but write whole
+    /// lines out byte for byte as they appear in the dump.
+    Raw,
+}
+
+/// Splits dump lines into [`Record`]s.
+///
+/// Fields are separated by any of the configured delimiters (`:` and `;`
+/// by default), but only up to the password: everything after the
+/// delimiter that follows the email or username is the password, so
+/// passwords keep their colons. With [`Layout::Auto`], lines whose first
+/// tab comes before their first delimiter (after any `scheme://`) are split
+/// on tabs, lines starting with a URL (`https://...` or a host
+/// followed by an email field) are read as `url:email:password`, lines
+/// whose first field holds an `@` as `email:password`, and anything else as
+/// `user:password`.
+#[derive(Debug, Clone)]
+pub struct RecordParser {
+    layout: Layout,
+    delimiters: Vec<char>,
+    invalid_utf8: InvalidUtf8,
+}
+
+impl Default for RecordParser {
+    fn default() -> Self {
+        RecordParser {
+            layout: Layout::Auto,
+            delimiters: vec![':', ';'],
+            invalid_utf8: InvalidUtf8::Lossy,
+        }
+    }
+}
+
+impl RecordParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn layout(mut self, layout: Layout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// Characters that may separate fields in non-tab-separated lines.
+    pub fn delimiters(mut self, delimiters: &[char]) -> Self {
+        self.delimiters = delimiters.to_vec();
+        self
+    }
+
+    /// How lines that are not valid UTF-8 are decoded for
+    /// [`RecordParser::parse_bytes`].
+    pub fn invalid_utf8(mut self, invalid_utf8: InvalidUtf8) -> Self {
+        self.invalid_utf8 = invalid_utf8;
+        self
+    }
+
+    pub fn parse(&self, line: impl Into<String>) -> Record {
+        let line = line.into();
+        let fields = self.fields(line.trim_end_matches(['\r', '\n']));
+        Record {
+            line,
+            raw: None,
+            invalid_utf8: self.invalid_utf8,
+            fields,
+        }
+    }
+
+    /// Parses a line read as raw bytes, decoding it as configured if it is
+    /// not valid UTF-8. The original bytes stay available through
+    /// [`Record::bytes`].
+    pub fn parse_bytes(&self, line: Vec<u8>) -> Record {
+        let raw = match String::from_utf8(line) {
+            Ok(line) => return self.parse(line),
+            Err(err) => err.into_bytes(),
+        };
+        let mut line = String::with_capacity(raw.len() + 8);
+        for chunk in raw.utf8_chunks() {
+            line.push_str(chunk.valid());
+            match self.invalid_utf8 {
+                InvalidUtf8::Hex => {
+                    for byte in chunk.invalid() {
+                        let _ = write!(line, "\\x{:02x}", byte);
+                    }
+                }
+                _ if !chunk.invalid().is_empty() => line.push(char::REPLACEMENT_CHARACTER),
+                _ => {}
+            }
+        }
+        Record {
+            raw: Some(raw),
+            ..self.parse(line)
+        }
+    }
+
+    fn fields(&self, line: &str) -> Fields {
+        let mut parsed = Fields::default();
+        let layout = match self.layout {
+            Layout::Auto if self.tab_separated(line) => Layout::Tsv,
+            Layout::Auto if self.url_end(line).is_some() => Layout::UrlEmailPass,
+            layout => layout,
+        };
+
+        match layout {
+            Layout::Tsv => {
+                let mut fields = field_ranges(line, '\t');
+                let email = fields
+                    .iter()
+                    .position(|field| line[field.clone()].contains('@'))
+                    .unwrap_or(0);
+                if email > 0 && looks_like_url(&line[fields[0].clone()]) {
+                    parsed.url = Some(fields[0].clone());
+                }
+                if email + 1 < fields.len() {
+                    parsed.password = Some(fields.remove(email + 1));
+                }
+                let user = fields.remove(email);
+                if line[user.clone()].contains('@') || self.layout == Layout::Tsv {
+                    parsed.email = Some(user);
+                } else {
+                    parsed.username = Some(user);
+                }
+                if parsed.url.is_some() {
+                    fields.remove(0);
+                }
+                parsed.extra = fields;
+            }
+            Layout::UrlEmailPass => {
+                let start = match self.url_end(line) {
+                    Some(end) => {
+                        parsed.url = Some(0..end);
+                        end + line[end..].chars().next().map_or(0, char::len_utf8)
+                    }
+                    None => 0,
+                };
+                self.user_and_password(&mut parsed, line, start);
+            }
+            Layout::EmailPass | Layout::UserPass | Layout::Auto => {
+                self.user_and_password(&mut parsed, line, 0);
+            }
+        }
+        parsed
+    }
+
+    /// Fills in the email or username starting at `start` and the password
+    /// after it.
+    fn user_and_password(&self, parsed: &mut Fields, line: &str, start: usize) {
+        let (user, password) = match self.split(line, start) {
+            Some((user, password)) => (user, Some(password)),
+            None => (start..line.len(), None),
+        };
+        let is_email = match self.layout {
+            Layout::EmailPass => true,
+            Layout::UserPass => false,
+            _ => line[user.clone()].contains('@'),
+        };
+        if is_email {
+            parsed.email = Some(user);
+        } else {
+            parsed.username = Some(user);
+        }
+        parsed.password = password;
+    }
+
+    /// Splits `line[start..]` at its first delimiter into the field before
+    /// it and everything after it.
+    fn split(&self, line: &str, start: usize) -> Option<(Range<usize>, Range<usize>)> {
+        let at = line[start..].find(self.delimiters.as_slice())? + start;
+        let delimiter = line[at..].chars().next()?.len_utf8();
+        Some((start..at, at + delimiter..line.len()))
+    }
+
+    /// Whether the first tab of `line` comes before its first delimiter, so
+    /// that a tab inside a password does not make the line tab-separated.
+    fn tab_separated(&self, line: &str) -> bool {
+        let start = scheme_end(line).unwrap_or(0);
+        match line[start..].find('\t') {
+            Some(tab) => !line[start..start + tab].contains(self.delimiters.as_slice()),
+            None => false,
+        }
+    }
+
+    /// Where a leading URL field ends: either an explicit `scheme://...` or
+    /// a host-like first field followed by an email field.
+    fn url_end(&self, line: &str) -> Option<usize> {
+        if let Some(host) = scheme_end(line) {
+            return Some(self.url_field_end(line, host));
+        }
+        let (first, rest) = self.split(line, 0)?;
+        let (second, _) = self.split(line, rest.start)?;
+        (looks_like_url(&line[first.clone()])
+            && !line[first.clone()].contains('@')
+            && line[second].contains('@'))
+        .then_some(first.end)
+    }
+
+    /// The end of a URL whose host starts at `from`: the first delimiter
+    /// that is not introducing a port number.
+    fn url_field_end(&self, line: &str, from: usize) -> usize {
+        let mut at = from;
+        while let Some((field, rest)) = self.split(line, at) {
+            let digits = line[rest.clone()]
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            let is_port = digits > 0 && line[rest.start + digits..].starts_with('/');
+            if !is_port {
+                return field.end;
+            }
+            at = rest.start + digits;
+        }
+        line.len()
+    }
+}
+
+/// Where the host of a line starting with `scheme://` begins.
+fn scheme_end(line: &str) -> Option<usize> {
+    let scheme = line.find("://")?;
+    (scheme > 0 && line[..scheme].chars().all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '.' || c == '-'))
+        .then_some(scheme + 3)
+}
+
+fn looks_like_url(field: &str) -> bool {
+    field.contains('.') || field.contains('/')
+}
+
+fn field_ranges(line: &str, delimiter: char) -> Vec<Range<usize>> {
+    let mut fields = Vec::new();
+    let mut start = 0;
+    for (at, _) in line.match_indices(delimiter) {
+        fields.push(start..at);
+        start = at + delimiter.len_utf8();
+    }
+    fields.push(start..line.len());
+    fields
+}
+
+/// A parsed dump line. Fields are views into the original line, which is
+/// kept as is.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Record {
+    line: String,
+    /// The undecoded line, when it was not valid UTF-8.
+    raw: Option<Vec<u8>>,
+    invalid_utf8: InvalidUtf8,
+    fields: Fields,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Fields {
+    email: Option<Range<usize>>,
+    username: Option<Range<usize>>,
+    password: Option<Range<usize>>,
+    url: Option<Range<usize>>,
+    extra: Vec<Range<usize>>,
+}
+
+impl Record {
+    /// The whole line, exactly as it appeared in the dump unless it had to
+    /// be decoded from invalid UTF-8.
+    pub fn line(&self) -> &str {
+        &self.line
+    }
+
+    /// The whole line as the bytes read from the dump.
+    pub fn bytes(&self) -> &[u8] {
+        self.raw.as_deref().unwrap_or(self.line.as_bytes())
+    }
+
+    /// How a line that was not valid UTF-8 was decoded.
+    pub fn invalid_utf8(&self) -> InvalidUtf8 {
+        self.invalid_utf8
+    }
+
+    pub fn email(&self) -> Option<&str> {
+        self.field(&self.fields.email)
+    }
+
+    /// The login name for records that have no email address.
+    pub fn username(&self) -> Option<&str> {
+        self.field(&self.fields.username)
+    }
+
+    pub fn password(&self) -> Option<&str> {
+        self.field(&self.fields.password)
+    }
+
+    pub fn url(&self) -> Option<&str> {
+        self.field(&self.fields.url)
+    }
+
+    /// Fields after the password in tab-separated dumps.
+    pub fn extra(&self) -> impl Iterator<Item = &str> {
+        self.fields.extra.iter().map(|range| &self.line[range.clone()])
+    }
+
+    /// The lowercased domain of the email address.
+    pub fn domain(&self) -> Option<String> {
+        let (_, domain) = self.email()?.rsplit_once('@')?;
+        (!domain.is_empty()).then(|| domain.to_lowercase())
+    }
+
+    /// Byte range of the email address within [`Record::line`].
+    pub fn email_range(&self) -> Option<Range<usize>> {
+        self.fields.email.clone()
+    }
+
+    /// Byte range of the password within [`Record::line`].
+    pub fn password_range(&self) -> Option<Range<usize>> {
+        self.fields.password.clone()
+    }
+
+    /// Maps a byte range of [`Record::line`] to the same text in
+    /// [`Record::bytes`].
+    pub fn raw_range(&self, range: Range<usize>) -> Range<usize> {
+        match &self.raw {
+            Some(raw) => {
+                raw_offset(raw, self.invalid_utf8, range.start)..raw_offset(raw, self.invalid_utf8, range.end)
+            }
+            None => range,
+        }
+    }
+
+    fn field(&self, range: &Option<Range<usize>>) -> Option<&str> {
+        range.as_ref().map(|range| &self.line[range.clone()])
+    }
+}
+
+/// The offset in `raw` of the decoded text at `offset`, where `raw` was
+/// decoded with `invalid_utf8`.
+fn raw_offset(raw: &[u8], invalid_utf8: InvalidUtf8, offset: usize) -> usize {
+    let (mut decoded, mut at) = (0, 0);
+    for chunk in raw.utf8_chunks() {
+        let valid = chunk.valid().len();
+        if offset <= decoded + valid {
+            return at + offset - decoded;
+        }
+        decoded += valid;
+        at += valid;
+        decoded += match invalid_utf8 {
+            InvalidUtf8::Hex => 4 * chunk.invalid().len(),
+            _ if chunk.invalid().is_empty() => 0,
+            _ => char::REPLACEMENT_CHARACTER.len_utf8(),
+        };
+        if offset < decoded {
+            return at;
+        }
+        at += chunk.invalid().len();
+    }
+    at
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The email, username, password and URL of `line` as auto-detected.
+    fn auto(line: &str) -> [Option<String>; 4] {
+        let record = RecordParser::new().parse(line);
+        [record.email(), record.username(), record.password(), record.url()].map(|field| field.map(String::from))
+    }
+
+    fn fields(fields: [Option<&str>; 4]) -> [Option<String>; 4] {
+        fields.map(|field| field.map(String::from))
+    }
+
+    #[test]
+    fn auto_email_pass() {
+        assert_eq!(auto("bob@x.com:hunter2"), fields([Some("bob@x.com"), None, Some("hunter2"), None]));
+        assert_eq!(auto("bob@x.com;hunter2"), fields([Some("bob@x.com"), None, Some("hunter2"), None]));
+    }
+
+    #[test]
+    fn auto_password_keeps_its_delimiters() {
+        assert_eq!(auto("bob@x.com:a:b;c"), fields([Some("bob@x.com"), None, Some("a:b;c"), None]));
+    }
+
+    #[test]
+    fn auto_user_pass() {
+        assert_eq!(auto("bob:hunter2"), fields([None, Some("bob"), Some("hunter2"), None]));
+    }
+
+    #[test]
+    fn auto_url_email_pass() {
+        assert_eq!(
+            auto("https://x.com:8443/login:bob@x.com:hunter2"),
+            fields([Some("bob@x.com"), None, Some("hunter2"), Some("https://x.com:8443/login")])
+        );
+        assert_eq!(
+            auto("x.com/login:bob@x.com:hunter2"),
+            fields([Some("bob@x.com"), None, Some("hunter2"), Some("x.com/login")])
+        );
+    }
+
+    #[test]
+    fn auto_tsv() {
+        let record = RecordParser::new().parse("bob@x.com\thunter2\tnote");
+        assert_eq!(record.email(), Some("bob@x.com"));
+        assert_eq!(record.password(), Some("hunter2"));
+        assert_eq!(record.extra().collect::<Vec<_>>(), ["note"]);
+        assert_eq!(
+            auto("https://x.com/login\tbob@x.com\thunter2"),
+            fields([Some("bob@x.com"), None, Some("hunter2"), Some("https://x.com/login")])
+        );
+    }
+
+    #[test]
+    fn auto_tab_inside_password_is_not_tsv() {
+        assert_eq!(auto("bob@x.com:pa\tss"), fields([Some("bob@x.com"), None, Some("pa\tss"), None]));
+        assert_eq!(auto("bob;pa\tss"), fields([None, Some("bob"), Some("pa\tss"), None]));
+    }
+
+    #[test]
+    fn invalid_utf8_keeps_raw_bytes() {
+        let record = RecordParser::new().parse_bytes(b"caf\xe9@x.com:pw\xff".to_vec());
+        assert_eq!(record.email(), Some("caf\u{fffd}@x.com"));
+        let password = record.raw_range(record.password_range().unwrap());
+        assert_eq!(&record.bytes()[password], b"pw\xff");
+    }
+}
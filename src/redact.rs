@@ -0,0 +1,126 @@
+use crate::record::Record;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::fmt::Write;
+use std::str::FromStr;
+
+/// How passwords are shown in every output format.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Redaction {
+    /// Passwords are written as found.
+    #[default]
+    None,
+    /// Only the first and last characters are kept, and every other one is
+    /// replaced by `*`, so `hunter2` becomes `h*****2`.
+    Mask,
+    /// Passwords are replaced by their hex SHA-256, or by their
+    /// HMAC-SHA-256 under `key` so that the digests cannot be looked up in
+    /// precomputed tables. Equal passwords still get equal digests.
+    Hash { key: Option<Vec<u8>> },
+    /// Passwords are left out.
+    Omit,
+}
+
+impl Redaction {
+    /// What to write instead of `password`, or `None` to leave it out.
+    pub fn password<'a>(&self, password: &'a str) -> Option<Cow<'a, str>> {
+        self.redact(password, password.as_bytes())
+    }
+
+    /// What to write instead of the record's password, or `None` if it has
+    /// none or it is left out. Hashes are of the password's original
+    /// bytes, so passwords that only differ in bytes that are not valid
+    /// UTF-8 get different digests.
+    pub fn record_password<'a>(&self, record: &'a Record) -> Option<Cow<'a, str>> {
+        let range = record.password_range()?;
+        self.redact(&record.line()[range.clone()], &record.bytes()[record.raw_range(range)])
+    }
+
+    /// Redacts `password`, decoded from the bytes `raw`.
+    fn redact<'a>(&self, password: &'a str, raw: &[u8]) -> Option<Cow<'a, str>> {
+        match self {
+            Redaction::None => Some(password.into()),
+            Redaction::Mask => Some(mask(password).into()),
+            Redaction::Hash { key } => Some(hash(raw, key.as_deref()).into()),
+            Redaction::Omit => None,
+        }
+    }
+
+    /// The record's line with its password redacted. An omitted password
+    /// leaves its delimiters in place, so `a@b.com:` stays recognisable.
+    pub fn line<'a>(&self, record: &'a Record) -> Cow<'a, str> {
+        let line = record.line();
+        let range = match record.password_range() {
+            Some(range) if *self != Redaction::None => range,
+            _ => return line.into(),
+        };
+        let replacement = self.record_password(record).unwrap_or_default();
+        format!("{}{}{}", &line[..range.start], replacement, &line[range.end..]).into()
+    }
+
+    /// Like [`Redaction::line`], but over the line's original bytes, which
+    /// need not be valid UTF-8.
+    pub fn raw_line<'a>(&self, record: &'a Record) -> Cow<'a, [u8]> {
+        let line = record.bytes();
+        let range = match record.password_range() {
+            Some(range) if *self != Redaction::None => range,
+            _ => return line.into(),
+        };
+        let replacement = self.record_password(record).unwrap_or_default();
+        let range = record.raw_range(range);
+        [&line[..range.start], replacement.as_bytes(), &line[range.end..]].concat().into()
+    }
+
+    /// The redaction to apply to passwords that already went through this
+    /// one, such as hits handed back by a [`crate::HitSpool`]: hashing a
+    /// digest again would change it, while masking or omitting again
+    /// changes nothing.
+    pub fn reapplied(&self) -> Redaction {
+        match self {
+            Redaction::Hash { .. } => Redaction::None,
+            redaction => redaction.clone(),
+        }
+    }
+}
+
+impl FromStr for Redaction {
+    type Err = String;
+
+    /// Parses `none`, `mask`, `hash` (without a key) or `omit`.
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        Ok(match mode {
+            "none" => Redaction::None,
+            "mask" => Redaction::Mask,
+            "hash" => Redaction::Hash { key: None },
+            "omit" => Redaction::Omit,
+            other => return Err(format!("unknown redaction mode '{}'", other)),
+        })
+    }
+}
+
+fn mask(password: &str) -> String {
+    let len = password.chars().count();
+    if len <= 2 {
+        return "*".repeat(len);
+    }
+    let mut chars = password.chars();
+    let first = chars.next().unwrap();
+    let last = chars.next_back().unwrap();
+    format!("{}{}{}", first, "*".repeat(len - 2), last)
+}
+
+fn hash(password: &[u8], key: Option<&[u8]>) -> String {
+    let digest: Vec<u8> = match key {
+        Some(key) => {
+            let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+            mac.update(password);
+            mac.finalize().into_bytes().to_vec()
+        }
+        None => Sha256::digest(password).to_vec(),
+    };
+    digest.iter().fold(String::with_capacity(64), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
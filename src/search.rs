@@ -0,0 +1,598 @@
+use crate::archive::{self, MEMBER_SEPARATOR};
+use crate::block::{self, Lines, Position, ScanUnit};
+use crate::dataset::Dataset;
+use crate::domain::{Account, DomainQuery};
+use crate::error::{AtPath, FileError};
+use crate::index::DomainIndex;
+use crate::record::{Record, RecordParser};
+use crate::shard;
+use crate::matcher::Matcher;
+use crate::stats::{LineCounter, Meter, Metered, ScanStats};
+use indicatif::ProgressBar;
+use rayon::prelude::*;
+use std::fs::{self, File};
+use std::io::{self, BufRead};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::ControlFlow;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// A single matching line from the dataset.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hit {
+    pub record: Record,
+    /// Name of the dataset the line came from.
+    pub dataset: String,
+    /// Path of the file the line came from, relative to the dataset root.
+    pub source: String,
+    /// 1-based line number within the decompressed file.
+    pub line_number: u64,
+    /// Byte offset of the start of the line within the decompressed file.
+    pub offset: u64,
+    /// The keywords, regexes, domain or address the line matched.
+    pub patterns: Vec<String>,
+}
+
+impl Hit {
+    /// The matching line, exactly as it appeared in the dump.
+    pub fn line(&self) -> &str {
+        self.record.line()
+    }
+}
+
+/// Runs keyword scans, domain searches and single or bulk email lookups
+/// against one or more [`Dataset`]s, which are searched in parallel.
+///
+/// A file that cannot be opened or decoded part way through, such as a
+/// truncated `.gz`, is skipped: the hits read from it before the error are
+/// kept, the rest of the dataset is still scanned, and the failure can be
+/// collected with [`Searcher::take_failures`]. A strict searcher stops at
+/// the first such file instead.
+pub struct Searcher {
+    datasets: Vec<Dataset>,
+    /// The parser for each dataset, with its manifest's delimiter.
+    parsers: Vec<RecordParser>,
+    meter: Meter,
+    strict: bool,
+    failures: Mutex<Vec<FileError>>,
+}
+
+impl Searcher {
+    pub fn new(dataset: Dataset) -> Self {
+        Searcher::across(vec![dataset])
+    }
+
+    /// A searcher over all of `datasets` at once, tagging each hit with
+    /// the name of the dataset it came from.
+    pub fn across(datasets: Vec<Dataset>) -> Self {
+        Searcher {
+            parsers: Vec::new(),
+            datasets,
+            meter: Meter::new(),
+            strict: false,
+            failures: Mutex::new(Vec::new()),
+        }
+        .with_parser(RecordParser::default())
+    }
+
+    /// Splits hits into records with `parser` instead of the default
+    /// auto-detecting one. A dataset whose manifest sets a delimiter is
+    /// still split on that.
+    pub fn with_parser(mut self, parser: RecordParser) -> Self {
+        self.parsers = self
+            .datasets
+            .iter()
+            .map(|dataset| dataset.manifest().configure(parser.clone()))
+            .collect();
+        self
+    }
+
+    /// Reports scan progress on `progress`: its position counts the
+    /// compressed bytes read, and its message the lines scanned per second.
+    pub fn with_progress(mut self, progress: ProgressBar) -> Self {
+        self.meter.progress = Some(progress);
+        self
+    }
+
+    /// Fails a search on the first unreadable file instead of skipping it.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    pub fn datasets(&self) -> &[Dataset] {
+        &self.datasets
+    }
+
+    /// The parser for each of [`Searcher::datasets`].
+    pub(crate) fn parsers(&self) -> &[RecordParser] {
+        &self.parsers
+    }
+
+    /// The files skipped since the last call because they could not be
+    /// read, with the error for each.
+    pub fn take_failures(&self) -> Vec<FileError> {
+        std::mem::take(&mut *self.failures.lock().unwrap())
+    }
+
+    /// The files, bytes and lines read and the hits found since the
+    /// searcher was created.
+    pub fn stats(&self) -> ScanStats {
+        self.meter.stats()
+    }
+
+    /// Records a failure to read a file, or returns it when strict.
+    fn skip(&self, err: FileError) -> Result<(), FileError> {
+        if self.strict {
+            return Err(err);
+        }
+        self.meter.add_skipped();
+        self.failures.lock().unwrap().push(err);
+        Ok(())
+    }
+
+    /// The data files of `dataset`, recording the directories that could
+    /// not be read, or failing at the first when strict.
+    fn files(&self, dataset: &Dataset) -> Result<Vec<PathBuf>, FileError> {
+        let (files, errors) = dataset.walk();
+        for err in errors {
+            self.skip(err)?;
+        }
+        Ok(files)
+    }
+
+    /// Scans every file in the datasets in parallel and returns the lines
+    /// that contain all of `keywords`.
+    ///
+    /// This buffers every hit; use [`Searcher::stream`] for scans whose
+    /// results may not fit in memory.
+    pub fn search_keywords<S: AsRef<str>>(&self, keywords: &[S]) -> Result<Vec<Hit>, FileError> {
+        self.search(&Matcher::keywords(keywords))
+    }
+
+    /// Scans every file in the datasets in parallel and returns the lines
+    /// accepted by `matcher`.
+    pub fn search(&self, matcher: &Matcher) -> Result<Vec<Hit>, FileError> {
+        let hits = Mutex::new(Vec::new());
+        self.stream(matcher, |hit| {
+            hits.lock().unwrap().push(hit);
+            ControlFlow::Continue(())
+        })?;
+        Ok(hits.into_inner().unwrap())
+    }
+
+    /// Scans every file in the datasets in parallel, handing each line
+    /// accepted by `matcher` to `sink` as soon as it is found.
+    ///
+    /// `sink` is called concurrently from the rayon workers, typically to
+    /// push into a bounded channel drained by a single writer thread. Once
+    /// it returns [`ControlFlow::Break`], say because the writer hung up,
+    /// every worker stops and the scan ends early without an error.
+    pub fn stream<F>(&self, matcher: &Matcher, sink: F) -> Result<(), FileError>
+    where
+        F: Fn(Hit) -> ControlFlow<()> + Sync,
+    {
+        let mut units = Vec::new();
+        for (i, dataset) in self.datasets.iter().enumerate() {
+            units.extend(self.files(dataset)?.into_iter().map(|path| (i, ScanUnit::file(path))));
+        }
+        self.scan(
+            units,
+            |_, line| {
+                let terms = matcher.find(line)?;
+                Some(terms.iter().map(|term| term.to_string()).collect())
+            },
+            sink,
+        )
+    }
+
+    /// Returns every account at the query's domain with the lines that
+    /// mention it, sorted by email.
+    pub fn search_domain(&self, query: &DomainQuery) -> Result<Vec<Account>, FileError> {
+        let mut accounts: BTreeMap<String, Vec<Hit>> = BTreeMap::new();
+        let hits = Mutex::new(Vec::new());
+        self.stream_domain(query, |hit| {
+            hits.lock().unwrap().push(hit);
+            ControlFlow::Continue(())
+        })?;
+        for hit in hits.into_inner().unwrap() {
+            let email = hit.record.email().unwrap_or_default().to_lowercase();
+            accounts.entry(email).or_default().push(hit);
+        }
+        Ok(accounts
+            .into_iter()
+            .map(|(email, hits)| Account { email, hits })
+            .collect())
+    }
+
+    /// Streams every line whose email belongs to the query's domain, until
+    /// `sink` returns [`ControlFlow::Break`].
+    pub fn stream_domain<F>(&self, query: &DomainQuery, sink: F) -> Result<(), FileError>
+    where
+        F: Fn(Hit) -> ControlFlow<()> + Sync,
+    {
+        let units = self.plan_units(query)?;
+        self.scan(
+            units,
+            |parser, line| {
+                (query.could_match(line) && query.matches(&parser.parse_bytes(line.to_vec())))
+                    .then(|| vec![query.domain().to_string()])
+            },
+            sink,
+        )
+    }
+
+    /// What a domain search has to read. Shards are keyed by the start of
+    /// the address rather than its domain, so without a [`DomainIndex`]
+    /// any shard may hold accounts at any domain and every file is planned;
+    /// with one, only the blocks indexed under the domain are.
+    pub fn plan_domain(&self, query: &DomainQuery) -> Result<Vec<ScanUnit>, FileError> {
+        Ok(self.plan_units(query)?.into_iter().map(|(_, unit)| unit).collect())
+    }
+
+    /// [`Searcher::plan_domain`], with the dataset of each unit.
+    fn plan_units(&self, query: &DomainQuery) -> Result<Vec<(usize, ScanUnit)>, FileError> {
+        let mut units = Vec::new();
+        for (i, dataset) in self.datasets.iter().enumerate() {
+            let planned = match DomainIndex::load(dataset)? {
+                Some(index) => index.plan(dataset, self.files(dataset)?, query)?,
+                None => self.files(dataset)?.into_iter().map(ScanUnit::file).collect(),
+            };
+            units.extend(planned.into_iter().map(|unit| (i, unit)));
+        }
+        Ok(units)
+    }
+
+    /// Reads `units`, each tagged with its dataset, in parallel, handing
+    /// `sink` a hit for every line `find` returns the matched patterns of.
+    /// `find` is also given the parser for the line's dataset. Workers stop
+    /// once `sink` breaks.
+    fn scan<P, F>(&self, units: Vec<(usize, ScanUnit)>, find: P, sink: F) -> Result<(), FileError>
+    where
+        P: Fn(&RecordParser, &[u8]) -> Option<Vec<String>> + Sync,
+        F: Fn(Hit) -> ControlFlow<()> + Sync,
+    {
+        let files: HashSet<&Path> = units.iter().map(|(_, unit)| unit.path.as_path()).collect();
+        self.meter.add_files(files.len() as u64);
+        let bytes = units
+            .iter()
+            .map(|(_, unit)| match unit.block {
+                Some(block) => block.len,
+                None => fs::metadata(&unit.path).map_or(0, |meta| meta.len()),
+            })
+            .sum();
+        self.meter.expect_bytes(bytes);
+
+        let stopped = AtomicBool::new(false);
+        let sink = |hit| {
+            if sink(hit).is_break() {
+                stopped.store(true, Ordering::Relaxed);
+            }
+        };
+        units.into_par_iter().try_for_each(|(i, unit)| {
+            if stopped.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            self.process_unit(i, &unit, &find, &sink, &stopped)
+                .at(&unit.path)
+                .or_else(|err| self.skip(err))
+        })
+    }
+
+    /// Returns every entry in the email's shard of each dataset that starts
+    /// with `email`, compared case-insensitively.
+    ///
+    /// Unless a manifest says its shards are unsorted, reading stops at the
+    /// first line past `email`, and a frame sidecar written by
+    /// [`DomainIndex::build`] lets it start at the right frame of a
+    /// multi-frame `.zst` shard. A dataset with no shard for the address
+    /// holds no entries for it, so it is a miss rather than a failure.
+    pub fn lookup_email(&self, email: &str) -> Result<Vec<Hit>, FileError> {
+        let email_lower = email.to_lowercase();
+        let hits = (0..self.datasets.len())
+            .into_par_iter()
+            .map(|i| self.lookup_in(i, email, &email_lower))
+            .collect::<Result<Vec<_>, FileError>>()?;
+        Ok(hits.into_iter().flatten().collect())
+    }
+
+    /// [`Searcher::lookup_email`] in the `i`th dataset.
+    fn lookup_in(&self, i: usize, email: &str, email_lower: &str) -> Result<Vec<Hit>, FileError> {
+        let dataset = &self.datasets[i];
+        let sorted = dataset.manifest().sort.is_sorted();
+        let path = match dataset.shard_path(email) {
+            Ok(path) => path,
+            Err(err) if err.error.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return self.skip(err).map(|()| Vec::new()),
+        };
+        let source = dataset.relative_path(&path);
+        let opened = File::open(&path).and_then(|file| {
+            self.meter.add_files(1);
+            let keys: &[&str] = if sorted { &[email_lower] } else { &[] };
+            shard::open_shard(&path, Metered::new(file, &self.meter), keys)
+        });
+        let (reader, start) = match opened {
+            Ok(opened) => opened,
+            Err(error) => return self.skip(FileError { path, error }).map(|()| Vec::new()),
+        };
+
+        let mut hits = Vec::new();
+        let mut counter = LineCounter::new(&self.meter);
+        for line in Lines::new(reader, start) {
+            counter.tick();
+            let (position, line) = match line {
+                Ok(line) => line,
+                Err(error) => {
+                    self.skip(FileError { path, error })?;
+                    break;
+                }
+            };
+            let text = String::from_utf8_lossy(&line);
+            if sorted && shard::sorts_after(&text, email_lower) {
+                break;
+            }
+            if shard::starts_with_ignore_case(&text, email_lower) {
+                hits.push(self.hit(i, line, &source, position, vec![email_lower.to_string()]));
+            }
+        }
+        Ok(hits)
+    }
+
+    /// Looks up many addresses at once, returning one [`Account`] per
+    /// distinct address in input order, with no hits for misses.
+    ///
+    /// Addresses are grouped by shard so that each shard is decompressed
+    /// once and all of its addresses are matched in a single pass; the
+    /// shards of every dataset are read in parallel. The addresses of a
+    /// missing shard are misses.
+    pub fn lookup_emails<S: AsRef<str>>(&self, emails: &[S]) -> Result<Vec<Account>, FileError> {
+        let mut seen = HashSet::new();
+        let mut emails_lower: Vec<String> = Vec::new();
+        for email in emails {
+            let email = email.as_ref().trim().to_lowercase();
+            if !email.is_empty() && seen.insert(email.clone()) {
+                emails_lower.push(email);
+            }
+        }
+
+        let mut shards: HashMap<(usize, PathBuf), Vec<usize>> = HashMap::new();
+        let mut failed: HashMap<PathBuf, FileError> = HashMap::new();
+        for (d, dataset) in self.datasets.iter().enumerate() {
+            for (i, email) in emails_lower.iter().enumerate() {
+                match dataset.shard_path(email) {
+                    Ok(path) => shards.entry((d, path)).or_default().push(i),
+                    Err(err) if err.error.kind() == io::ErrorKind::NotFound => {}
+                    Err(err) => {
+                        failed.entry(err.path.clone()).or_insert(err);
+                    }
+                }
+            }
+        }
+        for err in failed.into_values() {
+            self.skip(err)?;
+        }
+
+        let found: Vec<(usize, Hit)> = shards
+            .into_par_iter()
+            .map(|((d, path), wanted)| {
+                let dataset = &self.datasets[d];
+                let sorted = dataset.manifest().sort.is_sorted();
+                let keys: Vec<&str> = wanted.iter().map(|&i| emails_lower[i].as_str()).collect();
+                let last = keys.iter().max().copied().unwrap_or_default();
+                let source = dataset.relative_path(&path);
+                let opened = File::open(&path).and_then(|file| {
+                    self.meter.add_files(1);
+                    let keys = if sorted { keys.as_slice() } else { &[] };
+                    shard::open_shard(&path, Metered::new(file, &self.meter), keys)
+                });
+                let (reader, start) = match opened {
+                    Ok(opened) => opened,
+                    Err(error) => return self.skip(FileError { path, error }).map(|()| Vec::new()),
+                };
+                // ASCII addresses are found by looking up the line's prefix
+                // of each of their lengths; any others, whose case can
+                // change their length, are compared one by one.
+                let (ascii, other): (Vec<usize>, Vec<usize>) =
+                    wanted.into_iter().partition(|&i| emails_lower[i].is_ascii());
+                let ascii: HashMap<&[u8], usize> = ascii
+                    .into_iter()
+                    .map(|i| (emails_lower[i].as_bytes(), i))
+                    .collect();
+                let mut lengths: Vec<usize> = ascii.keys().map(|email| email.len()).collect();
+                lengths.sort_unstable();
+                lengths.dedup();
+
+                let mut found = Vec::new();
+                let mut prefix = Vec::new();
+                let mut counter = LineCounter::new(&self.meter);
+                for line in Lines::new(reader, start) {
+                    counter.tick();
+                    let (position, line) = match line {
+                        Ok(line) => line,
+                        Err(error) => {
+                            self.skip(FileError { path, error })?;
+                            break;
+                        }
+                    };
+                    let text = String::from_utf8_lossy(&line);
+                    // Checking the largest address first keeps this cheap
+                    // until the scan is nearly done.
+                    if sorted
+                        && shard::sorts_after(&text, last)
+                        && keys.iter().all(|key| shard::sorts_after(&text, key))
+                    {
+                        break;
+                    }
+                    let mut push = |i: usize| {
+                        let patterns = vec![emails_lower[i].clone()];
+                        found.push((i, self.hit(d, line.clone(), &source, position, patterns)));
+                    };
+                    for &len in &lengths {
+                        let Some(head) = text.as_bytes().get(..len) else {
+                            break;
+                        };
+                        prefix.clear();
+                        prefix.extend(head.iter().map(u8::to_ascii_lowercase));
+                        if let Some(&i) = ascii.get(prefix.as_slice()) {
+                            push(i);
+                        }
+                    }
+                    for &i in &other {
+                        if shard::starts_with_ignore_case(&text, &emails_lower[i]) {
+                            push(i);
+                        }
+                    }
+                }
+                Ok(found)
+            })
+            .collect::<Result<Vec<_>, FileError>>()?
+            .into_iter()
+            .flatten()
+            .collect();
+
+        let mut accounts: Vec<Account> = emails_lower
+            .into_iter()
+            .map(|email| Account { email, hits: Vec::new() })
+            .collect();
+        for (i, hit) in found {
+            accounts[i].hits.push(hit);
+        }
+        Ok(accounts)
+    }
+
+    /// Reads the record at a 1-based line of a data file back out, given
+    /// the `source` path relative to the dataset root that a [`Hit`]
+    /// reports, or `None` if the file is shorter than that.
+    ///
+    /// The file is looked for in the dataset named `dataset`, or else in
+    /// the first dataset that has it. Sources inside archives, such as
+    /// `dump.zip!/inner/path.txt`, are read by streaming the archive up to
+    /// that member.
+    pub fn record_at(
+        &self,
+        dataset: Option<&str>,
+        source: &str,
+        line_number: u64,
+    ) -> Result<Option<Hit>, FileError> {
+        let source = source.trim_start_matches("./");
+        let (file, member) = match source.split_once(MEMBER_SEPARATOR) {
+            Some((file, member)) => (file, Some(member)),
+            None => (source, None),
+        };
+        let relative = Path::new(file);
+        if !relative.components().all(|part| matches!(part, Component::Normal(_) | Component::CurDir)) {
+            return Err(FileError {
+                path: relative.to_path_buf(),
+                error: io::Error::new(io::ErrorKind::InvalidInput, "not a path within the dataset"),
+            });
+        }
+        let i = match dataset {
+            Some(name) => self.datasets.iter().position(|dataset| dataset.name() == name).ok_or_else(|| {
+                FileError {
+                    path: relative.to_path_buf(),
+                    error: io::Error::new(io::ErrorKind::NotFound, format!("no dataset named '{}'", name)),
+                }
+            })?,
+            None => self
+                .datasets
+                .iter()
+                .position(|dataset| dataset.root().join(relative).exists())
+                .unwrap_or_default(),
+        };
+        let path = self.datasets[i].root().join(relative);
+        let Some(member) = member else {
+            let (reader, start) = shard::open_at_line(&path, line_number).at(&path)?;
+            return self.line_at(i, reader, start, source, line_number).at(&path);
+        };
+        let mut found = None;
+        archive::for_each_member(File::open(&path).at(&path)?, |name, reader| {
+            if name == Some(member) && found.is_none() {
+                found = self.line_at(i, reader, Position::default(), source, line_number)?;
+            }
+            Ok(())
+        })
+        .at(&path)?;
+        Ok(found)
+    }
+
+    /// The hit for the 1-based line `line_number` of `reader`, which starts
+    /// at `start` in its file in the `i`th dataset.
+    fn line_at(
+        &self,
+        i: usize,
+        reader: impl BufRead,
+        start: Position,
+        source: &str,
+        line_number: u64,
+    ) -> io::Result<Option<Hit>> {
+        for line in Lines::new(reader, start) {
+            let (position, line) = line?;
+            if position.lines + 1 == line_number {
+                return Ok(Some(self.hit(i, line, source, position, Vec::new())));
+            }
+        }
+        Ok(None)
+    }
+
+    /// A hit for the line starting at `position` in the `i`th dataset.
+    fn hit(&self, i: usize, line: Vec<u8>, source: &str, position: Position, patterns: Vec<String>) -> Hit {
+        self.meter.add_hit();
+        Hit {
+            record: self.parsers[i].parse_bytes(line),
+            dataset: self.datasets[i].name().to_string(),
+            source: source.to_string(),
+            line_number: position.lines + 1,
+            offset: position.bytes,
+            patterns,
+        }
+    }
+
+    fn process_unit(
+        &self,
+        i: usize,
+        unit: &ScanUnit,
+        find: &impl Fn(&RecordParser, &[u8]) -> Option<Vec<String>>,
+        sink: &impl Fn(Hit),
+        stopped: &AtomicBool,
+    ) -> io::Result<()> {
+        let source = self.datasets[i].relative_path(&unit.path);
+        let file = Metered::new(File::open(&unit.path)?, &self.meter);
+        if let Some(block) = unit.block {
+            let reader = block::open_zstd_block(file, block)?;
+            return self.scan_lines(i, Lines::new(reader, block.start()), &source, find, sink, stopped);
+        }
+        archive::for_each_member(file, |member, reader| match member {
+            Some(member) => {
+                let source = format!("{}{}{}", source, MEMBER_SEPARATOR, member);
+                self.scan_lines(i, Lines::new(reader, Position::default()), &source, find, sink, stopped)
+            }
+            None => self.scan_lines(i, Lines::new(reader, Position::default()), &source, find, sink, stopped),
+        })
+    }
+
+    /// Hands `sink` the hits among `lines`, stopping early once `stopped`
+    /// is set.
+    fn scan_lines(
+        &self,
+        i: usize,
+        lines: Lines<impl BufRead>,
+        source: &str,
+        find: &impl Fn(&RecordParser, &[u8]) -> Option<Vec<String>>,
+        sink: &impl Fn(Hit),
+        stopped: &AtomicBool,
+    ) -> io::Result<()> {
+        let mut counter = LineCounter::new(&self.meter);
+        for line in lines {
+            if stopped.load(Ordering::Relaxed) {
+                break;
+            }
+            let (position, line) = line?;
+            counter.tick();
+            if let Some(patterns) = find(&self.parsers[i], &line) {
+                sink(self.hit(i, line, source, position, patterns));
+            }
+        }
+        Ok(())
+    }
+}
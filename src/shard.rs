@@ -0,0 +1,271 @@
+use crate::block::{Block, Position, Stamp};
+use crate::codec::{decode, open_decoded, Codec};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+/// Suffix of the frame sidecar written next to multi-frame `.zst` shards.
+/// Named after the tool, so that dataset files of its own are not taken
+/// for sidecars.
+pub(crate) const FRAMES_SUFFIX: &str = ".breach-parse.frames";
+
+/// Suffix of a file being written, until it is renamed into place.
+pub(crate) const TEMP_SUFFIX: &str = ".breach-parse.tmp";
+
+/// Sidecar listing the frames of a multi-frame `.zst` shard with the first
+/// line of each, so a lookup can seek straight to the frame where its
+/// address would be.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct FrameIndex {
+    stamp: Stamp,
+    frames: Vec<Frame>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Frame {
+    block: Block,
+    first_line: String,
+}
+
+impl FrameIndex {
+    pub(crate) fn new(stamp: Stamp, frames: Vec<(Block, String)>) -> Self {
+        FrameIndex {
+            stamp,
+            frames: frames
+                .into_iter()
+                .map(|(block, first_line)| Frame { block, first_line })
+                .collect(),
+        }
+    }
+
+    pub(crate) fn sidecar_path(shard: &Path) -> PathBuf {
+        let mut path = shard.as_os_str().to_owned();
+        path.push(FRAMES_SUFFIX);
+        PathBuf::from(path)
+    }
+
+    /// Loads the sidecar of `shard`, or `None` if there is none, it was
+    /// written by an older version, or the shard has changed since.
+    pub(crate) fn load(shard: &Path) -> io::Result<Option<Self>> {
+        let file = match File::open(Self::sidecar_path(shard)) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let Ok(index) = serde_json::from_reader::<_, FrameIndex>(BufReader::new(file)) else {
+            return Ok(None);
+        };
+        Ok((index.stamp == Stamp::of(shard)?).then_some(index))
+    }
+
+    pub(crate) fn save(&self, shard: &Path) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(Self::sidecar_path(shard))?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()
+    }
+
+    /// The first frame that may hold lines starting with `key`: every
+    /// earlier frame ends before the next frame's first line, which already
+    /// sorts before any match.
+    fn start_block(&self, key: &str) -> Option<Block> {
+        let skip = self
+            .frames
+            .iter()
+            .skip(1)
+            .take_while(|frame| sorts_before(&frame.first_line, key))
+            .count();
+        self.frames.get(skip).map(|frame| frame.block)
+    }
+
+    /// The frame holding the 1-based line `line_number`.
+    fn block_for_line(&self, line_number: u64) -> Option<Block> {
+        self.frames
+            .iter()
+            .take_while(|frame| frame.block.start_line < line_number)
+            .last()
+            .map(|frame| frame.block)
+    }
+}
+
+/// Reads `file`, the email shard at `path`, positioned, for a zstd shard,
+/// at the earliest frame its frame sidecar allows for any of the
+/// lowercased `keys`. Also returns where in the file reading starts.
+pub(crate) fn open_shard<'a>(
+    path: &Path,
+    mut file: impl Read + Seek + 'a,
+    keys: &[&str],
+) -> io::Result<(Box<dyn BufRead + 'a>, Position)> {
+    if Codec::of(path)? != Codec::Zstd {
+        return Ok((decode(BufReader::new(file))?, Position::default()));
+    }
+    let mut start = Position::default();
+    if let Some(frames) = FrameIndex::load(path)? {
+        let block = keys
+            .iter()
+            .filter_map(|key| frames.start_block(key))
+            .min_by_key(|block| block.offset);
+        if let Some(block) = block {
+            file.seek(SeekFrom::Start(block.offset))?;
+            start = block.start();
+        }
+    }
+    Ok((Box::new(BufReader::new(ZstdDecoder::new(file)?)), start))
+}
+
+/// Opens any data file positioned at or before its 1-based line
+/// `line_number`, seeking to the right frame when a zstd file has a
+/// frame sidecar. Also returns where in the file reading starts.
+pub(crate) fn open_at_line(path: &Path, line_number: u64) -> io::Result<(Box<dyn BufRead>, Position)> {
+    if Codec::of(path)? == Codec::Zstd {
+        if let Some(block) = FrameIndex::load(path)?.and_then(|frames| frames.block_for_line(line_number)) {
+            let mut file = File::open(path)?;
+            file.seek(SeekFrom::Start(block.offset))?;
+            return Ok((Box::new(BufReader::new(ZstdDecoder::new(file)?)), block.start()));
+        }
+    }
+    Ok((open_decoded(path)?, Position::default()))
+}
+
+// Shards are sorted either bytewise (so `ALICE` < `Alice` < `alice`) or by
+// their lowercased lines. The two checks below are safe under both orders,
+// for a lowercased `key` and lines matching it case-insensitively.
+
+/// Whether `line` sorts before every line starting with `key`.
+pub(crate) fn sorts_before(line: &str, key: &str) -> bool {
+    line < key.to_ascii_uppercase().as_str() && line.to_lowercase().as_str() < key
+}
+
+/// Whether `line` and every line after it sort after all lines starting
+/// with `key`, so a scan for `key` can stop.
+pub(crate) fn sorts_after(line: &str, key: &str) -> bool {
+    let prefix = &line.as_bytes()[..line.len().min(key.len())];
+    prefix.is_ascii() && !prefix.iter().any(u8::is_ascii_uppercase) && prefix > key.as_bytes()
+}
+
+/// Whether `line` starts with the lowercased `key`, ignoring case.
+pub(crate) fn starts_with_ignore_case(line: &str, key: &str) -> bool {
+    if key.is_ascii() {
+        line.as_bytes()
+            .get(..key.len())
+            .is_some_and(|prefix| prefix.eq_ignore_ascii_case(key.as_bytes()))
+    } else {
+        line.to_lowercase().starts_with(key)
+    }
+}
+
+/// Writes a frame sidecar for `path` if it is a `.zst` shard with more than
+/// one frame, and removes a stale one otherwise.
+pub(crate) fn write_sidecar(path: &Path, stamp: Stamp, frames: Vec<(Block, String)>) -> io::Result<()> {
+    if frames.len() > 1 {
+        return FrameIndex::new(stamp, frames).save(path);
+    }
+    match fs::remove_file(FrameIndex::sidecar_path(path)) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// Decompressed bytes per frame of the shards [`ShardWriter`] writes, small
+/// enough that a lookup seeking by the frame sidecar decodes little.
+const FRAME_LEN: usize = 1 << 20;
+
+/// Writes a sorted shard in `codec` as a series of independently
+/// compressed frames, with a frame sidecar when it is zstd. Lines go to
+/// a temporary file next to the shard, which replaces the shard only once
+/// complete, so a crash never leaves half a shard; the temporary file is
+/// removed if the shard is never finished.
+pub(crate) struct ShardWriter {
+    path: PathBuf,
+    temp: PathBuf,
+    codec: Codec,
+    file: BufWriter<File>,
+    frame: Vec<u8>,
+    first_line: String,
+    frame_start: Position,
+    position: Position,
+    offset: u64,
+    frames: Vec<(Block, String)>,
+    finished: bool,
+}
+
+impl ShardWriter {
+    pub(crate) fn create(path: &Path, codec: Codec) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(TEMP_SUFFIX);
+        let temp = PathBuf::from(temp);
+        Ok(ShardWriter {
+            path: path.to_path_buf(),
+            file: BufWriter::new(File::create(&temp)?),
+            temp,
+            codec,
+            frame: Vec::with_capacity(FRAME_LEN),
+            first_line: String::new(),
+            frame_start: Position::default(),
+            position: Position::default(),
+            offset: 0,
+            frames: Vec::new(),
+            finished: false,
+        })
+    }
+
+    pub(crate) fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.frame.is_empty() {
+            self.first_line = String::from_utf8_lossy(line).into_owned();
+            self.frame_start = self.position;
+        }
+        self.frame.extend_from_slice(line);
+        self.frame.push(b'\n');
+        self.position.lines += 1;
+        self.position.bytes += line.len() as u64 + 1;
+        if self.frame.len() >= FRAME_LEN {
+            self.end_frame()?;
+        }
+        Ok(())
+    }
+
+    fn end_frame(&mut self) -> io::Result<()> {
+        let compressed = self.codec.encode(&self.frame)?;
+        self.file.write_all(&compressed)?;
+        let block = Block {
+            offset: self.offset,
+            len: compressed.len() as u64,
+            start_line: self.frame_start.lines,
+            start_offset: self.frame_start.bytes,
+        };
+        self.frames.push((block, std::mem::take(&mut self.first_line)));
+        self.offset += block.len;
+        self.frame.clear();
+        Ok(())
+    }
+
+    /// Completes the shard, replacing any previous file at its path, and
+    /// returns how many lines it holds.
+    pub(crate) fn finish(mut self) -> io::Result<u64> {
+        if !self.frame.is_empty() {
+            self.end_frame()?;
+        }
+        self.file.flush()?;
+        self.file.get_ref().sync_all()?;
+        fs::rename(&self.temp, &self.path)?;
+        self.finished = true;
+        let frames = match self.codec {
+            Codec::Zstd => std::mem::take(&mut self.frames),
+            _ => Vec::new(),
+        };
+        write_sidecar(&self.path, Stamp::of(&self.path)?, frames)?;
+        Ok(self.position.lines)
+    }
+}
+
+impl Drop for ShardWriter {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = fs::remove_file(&self.temp);
+        }
+    }
+}
@@ -0,0 +1,342 @@
+use rayon::slice::ParallelSliceMut;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use zstd::stream::read::Decoder as ZstdDecoder;
+use zstd::stream::write::Encoder as ZstdEncoder;
+
+/// What a buffered entry costs beyond its bytes.
+const ENTRY_OVERHEAD: usize = std::mem::size_of::<Vec<u8>>();
+
+/// zstd level for sorted runs, which only live until the merge.
+const RUN_LEVEL: i32 = 1;
+
+/// Most runs read at once while merging, so that merges of many runs stay
+/// within the limit on open files.
+const MERGE_FAN_IN: usize = 64;
+
+type Entries = Box<dyn Iterator<Item = io::Result<Vec<u8>>> + Send>;
+
+/// Sorts more byte strings than fit in memory: entries are buffered up to
+/// a memory limit, then sorted and spilled to a compressed run file in a
+/// temporary directory, and [`ExternalSorter::finish`] merges the runs, at
+/// most [`MERGE_FAN_IN`] at a time.
+///
+/// Several threads can push batches at once. Once the buffer holds half
+/// the memory limit it is taken out and spilled while the next one fills;
+/// only one buffer is spilled at a time, and pushers that fill the next
+/// one before then wait for it, so entries stay within the limit apart from
+/// the batches being pushed.
+///
+/// Entries must not contain `\n`, which separates them in run files.
+pub(crate) struct ExternalSorter {
+    dir: TempDir,
+    buffer: Mutex<Buffer>,
+    memory_limit: usize,
+    dedup: bool,
+    runs: Mutex<Vec<PathBuf>>,
+    /// Held from taking a full buffer until it is spilled, so only one is
+    /// out of the buffer at a time.
+    spilling: Mutex<()>,
+    next_run: AtomicUsize,
+}
+
+#[derive(Default)]
+struct Buffer {
+    entries: Vec<Vec<u8>>,
+    bytes: usize,
+}
+
+impl ExternalSorter {
+    /// A sorter holding up to `memory_limit` bytes of entries, spilling
+    /// runs to a new directory under `temp_dir`.
+    pub(crate) fn new(temp_dir: &Path, memory_limit: usize) -> io::Result<Self> {
+        Ok(ExternalSorter {
+            dir: TempDir::new(temp_dir)?,
+            buffer: Mutex::new(Buffer::default()),
+            memory_limit,
+            dedup: false,
+            runs: Mutex::new(Vec::new()),
+            spilling: Mutex::new(()),
+            next_run: AtomicUsize::new(0),
+        })
+    }
+
+    /// Drops entries equal to one already pushed.
+    pub(crate) fn dedup(mut self) -> Self {
+        self.dedup = true;
+        self
+    }
+
+    /// The directory runs are spilled to, for error messages.
+    pub(crate) fn path(&self) -> &Path {
+        &self.dir.0
+    }
+
+    pub(crate) fn push(&self, entry: Vec<u8>) -> io::Result<()> {
+        self.push_batch(std::iter::once(entry))
+    }
+
+    /// Pushes every entry of `batch` under a single lock.
+    pub(crate) fn push_batch(&self, batch: impl IntoIterator<Item = Vec<u8>>) -> io::Result<()> {
+        let full = {
+            let mut buffer = self.buffer.lock().unwrap();
+            for entry in batch {
+                buffer.bytes += entry.len() + ENTRY_OVERHEAD;
+                buffer.entries.push(entry);
+            }
+            self.is_full(&buffer)
+        };
+        if full {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn is_full(&self, buffer: &Buffer) -> bool {
+        buffer.bytes >= self.memory_limit / 2
+    }
+
+    fn dedup_sorted(&self, entries: &mut Vec<Vec<u8>>) {
+        if self.dedup {
+            entries.dedup();
+        }
+    }
+
+    /// Spills the buffer if it is still full once any spill under way has
+    /// ended.
+    fn spill(&self) -> io::Result<()> {
+        let _spilling = self.spilling.lock().unwrap();
+        let mut entries = {
+            let mut buffer = self.buffer.lock().unwrap();
+            if !self.is_full(&buffer) {
+                return Ok(());
+            }
+            std::mem::take(&mut *buffer).entries
+        };
+        // Sorted on this thread: a parallel sort could have it pick up
+        // another push while holding the lock.
+        entries.sort_unstable();
+        self.dedup_sorted(&mut entries);
+        let path = self.write_run(entries.into_iter().map(Ok))?;
+        self.runs.lock().unwrap().push(path);
+        Ok(())
+    }
+
+    /// Writes `entries`, which must be sorted, to a new run file.
+    fn write_run(&self, entries: impl Iterator<Item = io::Result<Vec<u8>>>) -> io::Result<PathBuf> {
+        let run = self.next_run.fetch_add(1, Ordering::Relaxed);
+        let path = self.dir.0.join(format!("run-{}.zst", run));
+        let mut writer = ZstdEncoder::new(BufWriter::new(create_private(&path)?), RUN_LEVEL)?;
+        for entry in entries {
+            writer.write_all(&entry?)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.finish()?.flush()?;
+        Ok(path)
+    }
+
+    /// Every entry pushed, in ascending byte order.
+    pub(crate) fn finish(self) -> io::Result<Sorted> {
+        let mut buffer = std::mem::take(&mut *self.buffer.lock().unwrap()).entries;
+        let mut runs = std::mem::take(&mut *self.runs.lock().unwrap());
+        buffer.par_sort_unstable();
+        self.dedup_sorted(&mut buffer);
+        // Merge runs into longer ones until they and the buffer can be
+        // read at once.
+        while runs.len() >= MERGE_FAN_IN {
+            let merged: Vec<PathBuf> = runs.drain(..MERGE_FAN_IN).collect();
+            let path = self.write_run(Merge::new(open_runs(&merged)?, self.dedup)?)?;
+            for run in merged {
+                fs::remove_file(run)?;
+            }
+            runs.push(path);
+        }
+        let mut sources = open_runs(&runs)?;
+        sources.push(Box::new(buffer.into_iter().map(Ok)));
+        Ok(Sorted {
+            merge: Merge::new(sources, self.dedup)?,
+            _dir: self.dir,
+        })
+    }
+}
+
+fn open_runs(runs: &[PathBuf]) -> io::Result<Vec<Entries>> {
+    let mut sources: Vec<Entries> = Vec::with_capacity(runs.len() + 1);
+    for path in runs {
+        sources.push(Box::new(RunReader {
+            reader: BufReader::new(ZstdDecoder::new(File::open(path)?)?),
+        }));
+    }
+    Ok(sources)
+}
+
+/// The merged entries of an [`ExternalSorter`]. Its run files are removed
+/// when it is dropped.
+pub(crate) struct Sorted {
+    merge: Merge,
+    _dir: TempDir,
+}
+
+impl Iterator for Sorted {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.merge.next()
+    }
+}
+
+/// Merges sorted sources of entries into one sorted sequence.
+struct Merge {
+    sources: Vec<Entries>,
+    heap: BinaryHeap<Reverse<(Vec<u8>, usize)>>,
+    dedup: bool,
+    last: Option<Vec<u8>>,
+}
+
+impl Merge {
+    fn new(sources: Vec<Entries>, dedup: bool) -> io::Result<Self> {
+        let mut merge = Merge {
+            sources,
+            heap: BinaryHeap::new(),
+            dedup,
+            last: None,
+        };
+        for i in 0..merge.sources.len() {
+            merge.refill(i)?;
+        }
+        Ok(merge)
+    }
+
+    /// Moves the next entry of source `i` onto the heap.
+    fn refill(&mut self, i: usize) -> io::Result<()> {
+        if let Some(entry) = self.sources[i].next() {
+            self.heap.push(Reverse((entry?, i)));
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for Merge {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let Reverse((entry, i)) = self.heap.pop()?;
+            if let Err(err) = self.refill(i) {
+                return Some(Err(err));
+            }
+            if self.dedup && self.last.as_ref() == Some(&entry) {
+                continue;
+            }
+            if self.dedup {
+                self.last = Some(entry.clone());
+            }
+            return Some(Ok(entry));
+        }
+    }
+}
+
+/// The entries of a run file.
+struct RunReader<R> {
+    reader: R,
+}
+
+impl<R: BufRead> Iterator for RunReader<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut entry = Vec::new();
+        match self.reader.read_until(b'\n', &mut entry) {
+            Ok(0) => None,
+            Ok(_) => {
+                if entry.last() == Some(&b'\n') {
+                    entry.pop();
+                }
+                Some(Ok(entry))
+            }
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+/// Creates a run file only its owner can read, as runs may hold
+/// credentials.
+fn create_private(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)
+}
+
+/// A directory of scratch files, removed with everything in it on drop.
+/// Only its owner can enter it.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(base: &Path) -> io::Result<Self> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        loop {
+            let n = NEXT.fetch_add(1, Ordering::Relaxed);
+            let path = base.join(format!("breach-parse-sort-{}-{}", process::id(), n));
+            let mut builder = DirBuilder::new();
+            #[cfg(unix)]
+            std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+            match builder.create(&path) {
+                Ok(()) => return Ok(TempDir(path)),
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A directory under the system's temporary directory for tests to write
+/// datasets into, removed on drop.
+#[cfg(test)]
+pub(crate) struct Scratch(TempDir);
+
+#[cfg(test)]
+impl Scratch {
+    pub(crate) fn new() -> Self {
+        Scratch(TempDir::new(&std::env::temp_dir()).unwrap())
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.0 .0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_more_runs_than_it_opens_at_once() {
+        let scratch = Scratch::new();
+        // Every push fills the buffer, so each entry is a run of its own.
+        let sorter = ExternalSorter::new(scratch.path(), 1).unwrap().dedup();
+        let entries: Vec<Vec<u8>> = (0..3 * MERGE_FAN_IN)
+            .map(|n| format!("{:04}", (n * 37) % 150).into_bytes())
+            .collect();
+        entries.iter().cloned().try_for_each(|entry| sorter.push(entry)).unwrap();
+
+        let sorted: Vec<Vec<u8>> = sorter.finish().unwrap().map(Result::unwrap).collect();
+        let mut expected = entries;
+        expected.sort();
+        expected.dedup();
+        assert_eq!(sorted, expected);
+    }
+}
@@ -0,0 +1,160 @@
+use indicatif::{HumanCount, ProgressBar};
+use serde::Serialize;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+/// Lines counted locally before they are added to the shared counter and
+/// the progress readout refreshed.
+const LINE_BATCH: u64 = 1 << 16;
+
+/// What a [`crate::Searcher`] has read and found since it was created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct ScanStats {
+    pub files: u64,
+    /// Bytes read from disk, before decompression.
+    pub bytes: u64,
+    pub lines: u64,
+    pub hits: u64,
+    /// Files skipped because they could not be read.
+    pub skipped: u64,
+    pub elapsed_secs: f64,
+}
+
+impl ScanStats {
+    pub fn lines_per_sec(&self) -> f64 {
+        self.lines as f64 / self.elapsed_secs.max(f64::EPSILON)
+    }
+
+    pub fn bytes_per_sec(&self) -> f64 {
+        self.bytes as f64 / self.elapsed_secs.max(f64::EPSILON)
+    }
+}
+
+/// Shared counters behind [`ScanStats`], updated by the scanning workers,
+/// which also drive the progress bar: its position is in compressed bytes
+/// and its message shows the line rate.
+#[derive(Debug)]
+pub(crate) struct Meter {
+    files: AtomicU64,
+    bytes: AtomicU64,
+    lines: AtomicU64,
+    hits: AtomicU64,
+    skipped: AtomicU64,
+    started: Instant,
+    pub(crate) progress: Option<ProgressBar>,
+}
+
+impl Meter {
+    pub(crate) fn new() -> Self {
+        Meter {
+            files: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            lines: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            skipped: AtomicU64::new(0),
+            started: Instant::now(),
+            progress: None,
+        }
+    }
+
+    /// Sets the number of compressed bytes the progress bar counts up to.
+    pub(crate) fn expect_bytes(&self, bytes: u64) {
+        if let Some(progress) = &self.progress {
+            progress.set_length(bytes);
+        }
+    }
+
+    pub(crate) fn add_files(&self, files: u64) {
+        self.files.fetch_add(files, Ordering::Relaxed);
+    }
+
+    fn add_bytes(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        if let Some(progress) = &self.progress {
+            progress.inc(bytes);
+        }
+    }
+
+    pub(crate) fn add_lines(&self, lines: u64) {
+        let total = self.lines.fetch_add(lines, Ordering::Relaxed) + lines;
+        if let Some(progress) = &self.progress {
+            let rate = total as f64 / progress.elapsed().as_secs_f64().max(f64::EPSILON);
+            progress.set_message(format!("{} lines/s", HumanCount(rate as u64)));
+        }
+    }
+
+    pub(crate) fn add_hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_skipped(&self) {
+        self.skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(&self) -> ScanStats {
+        ScanStats {
+            files: self.files.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            lines: self.lines.load(Ordering::Relaxed),
+            hits: self.hits.load(Ordering::Relaxed),
+            skipped: self.skipped.load(Ordering::Relaxed),
+            elapsed_secs: self.started.elapsed().as_secs_f64(),
+        }
+    }
+}
+
+/// Counts the lines of one file in batches, so workers do not contend on
+/// the shared counter for every line.
+pub(crate) struct LineCounter<'a> {
+    meter: &'a Meter,
+    pending: u64,
+}
+
+impl<'a> LineCounter<'a> {
+    pub(crate) fn new(meter: &'a Meter) -> Self {
+        LineCounter { meter, pending: 0 }
+    }
+
+    pub(crate) fn tick(&mut self) {
+        self.pending += 1;
+        if self.pending == LINE_BATCH {
+            self.meter.add_lines(self.pending);
+            self.pending = 0;
+        }
+    }
+}
+
+impl Drop for LineCounter<'_> {
+    fn drop(&mut self) {
+        if self.pending > 0 {
+            self.meter.add_lines(self.pending);
+        }
+    }
+}
+
+/// A reader that adds the bytes read through it to a [`Meter`].
+pub(crate) struct Metered<'a, R> {
+    inner: R,
+    meter: &'a Meter,
+}
+
+impl<'a, R> Metered<'a, R> {
+    pub(crate) fn new(inner: R, meter: &'a Meter) -> Self {
+        Metered { inner, meter }
+    }
+}
+
+impl<R: Read> Read for Metered<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.meter.add_bytes(read as u64);
+        Ok(read)
+    }
+}
+
+impl<R: Seek> Seek for Metered<'_, R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}